@@ -0,0 +1,42 @@
+// Criterion benchmarks of the hot paths, run with cargo bench. All inputs come from
+// fixed seed, so runs are comparable
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use rand::{SeedableRng, rngs::StdRng};
+use light_probles_calculation::spherical::Direction;
+use light_probles_calculation::spherical_harmonics::SHFuncApproximation;
+
+fn sh_benchmarks(c: &mut Criterion) {
+	let mut rng = StdRng::seed_from_u64(0);
+	let directions: Vec<Direction> = (0..1024).map(|_i| Direction::generate_random_on_sphere(&mut rng)).collect();
+	let sh = SHFuncApproximation::from_function(|x, y, z| 1f32 + x * y + z, &mut rng, 1000);
+
+	let mut basis = SHFuncApproximation::new();
+	let mut index = 0;
+	c.bench_function("from_direction", |b| b.iter(|| {
+		index = (index + 1) % directions.len();
+		basis.from_direction(black_box(directions[index]));
+		black_box(&basis);
+	}));
+
+	let mut workspace = SHFuncApproximation::new();
+	c.bench_function("eval (workspace reused)", |b| b.iter(|| {
+		index = (index + 1) % directions.len();
+		black_box(sh.eval(black_box(directions[index]), &mut workspace))
+	}));
+
+	c.bench_function("eval (new workspace)", |b| b.iter(|| {
+		index = (index + 1) % directions.len();
+		let mut workspace = SHFuncApproximation::new();
+		black_box(sh.eval(black_box(directions[index]), &mut workspace))
+	}));
+
+	for count in [1000u32, 10000u32].iter() {
+		let mut rng = StdRng::seed_from_u64(0);
+		c.bench_function(&format!("from_function ({0} samples)", count), |b| b.iter(|| {
+			black_box(SHFuncApproximation::from_function(|x, y, z| 1f32 + x * y + z, &mut rng, *count))
+		}));
+	}
+}
+
+criterion_group!(benches, sh_benchmarks);
+criterion_main!(benches);
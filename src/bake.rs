@@ -0,0 +1,220 @@
+use rand::{Rng, SeedableRng, rngs::StdRng};
+use crate::spherical::Direction;
+use crate::spherical_harmonics::SHFuncApproximation;
+#[cfg(feature = "parallel")]
+use crate::spherical_harmonics_color::SHColorApproximation;
+
+
+// Parameters of Monte Carlo projection used by bake
+#[derive(Debug, Clone, Copy)]
+pub struct ProjectionConfig {
+	// Highest SH band of the result
+	pub order: usize,
+	// Number of random directions
+	pub samples: u32,
+	// Seed of the rng, same seed gives the same result
+	pub seed: u64,
+	// Sample values are clamped to this magnitude, trading a small bias for stability of
+	// HDR sources where a few texels (sun) can overflow the f32 accumulation
+	pub max_sample_value: Option<f32>
+}
+
+impl Default for ProjectionConfig {
+	fn default() -> ProjectionConfig {
+		ProjectionConfig { order: 2, samples: 10000, seed: 0, max_sample_value: None }
+	}
+}
+
+// Bakes irradiance probe from radiance arriving from each direction. Radiance is
+// projected and convolved with clamped cosine, so eval at normal n gives the diffuse
+// irradiance of a surface with that normal
+pub fn bake<F>(radiance: F, config: ProjectionConfig) -> SHFuncApproximation
+	where F: Fn(Direction) -> f32 {
+
+	let max = config.max_sample_value.unwrap_or(f32::INFINITY);
+	let mut rng = StdRng::seed_from_u64(config.seed);
+	let mut approximation = SHFuncApproximation::from_function_with_order(
+		config.order, |x, y, z| radiance(Direction { x, y, z }).clamp(-max, max), &mut rng, config.samples);
+	approximation.convolve_cosine_lobe_in_place();
+	approximation
+}
+
+// Projects visibility function (1 where the sky is visible, 0 where occluded) into SH.
+// Convolution of the result with radiance SH gives the integral of visible radiance,
+// which is how occlusion is combined with lighting. To get occluded irradiance for a
+// normal, apply_directional_cosine the radiance first
+pub fn bake_visibility_sh<V, R>(visibility: V, rng: &mut R, count: u32) -> SHFuncApproximation
+	where V: Fn(f32, f32, f32) -> f32, R: Rng {
+
+	SHFuncApproximation::from_function(visibility, rng, count)
+}
+
+// Projects cone visibility for baked soft shadows. cone_cosine gives per direction the
+// cosine term of the unoccluded cone, with 1 for full visibility. The field is smooth,
+// so few bands represent it well. Consumers reconstruct it at a normal and map it to
+// a soft occlusion factor
+pub fn bake_cone_visibility_sh<F, R>(cone_cosine: F, rng: &mut R, count: u32) -> SHFuncApproximation
+	where F: Fn(f32, f32, f32) -> f32, R: Rng {
+
+	SHFuncApproximation::from_function(cone_cosine, rng, count)
+}
+
+// Bakes directional occlusion in tangent space, where the surface normal is +Z. We
+// project 4 V(dir) max(dir.z, 0), so that the average of the reconstruction (the L0
+// band) is the cosine weighted ambient occlusion, and the L1 band points along the
+// bent normal (use dominant_direction). Only the upper hemisphere is sampled
+pub fn bake_bent_normal_sh<V, R>(visibility: V, mut rng: &mut R, count: u32) -> SHFuncApproximation
+	where V: Fn(f32, f32, f32) -> f32, R: Rng {
+
+	let normal = Direction::new(0f32, 0f32, 1f32);
+	let samples = (0..count).map(|_| {
+		let d = Direction::generate_random_on_hemisphere(&normal, &mut rng);
+
+		// Hemisphere pdf is twice the sphere pdf assumed by from_iter_samples
+		(d, 4f32 * visibility(d.x, d.y, d.z) * d.z / 2f32)
+	});
+	SHFuncApproximation::from_iter_samples(samples)
+}
+
+// Bakes outdoor lighting, split into a sun and sky. Sun is too small to be hit by
+// random samples, so we add it analytically as a directional delta with given
+// sun_intensity (radiance integrated over the sun disk). Only the sky is projected
+// with Monte Carlo sampling
+pub fn bake_sun_sky_sh<F, R>(sun_direction: Direction, sun_intensity: f32, sky: F, rng: &mut R, count: u32) -> SHFuncApproximation
+	where F: Fn(f32, f32, f32) -> f32, R: Rng {
+
+	let mut approximation = SHFuncApproximation::from_function(sky, rng, count);
+
+	approximation.add_in_place_scaled(&SHFuncApproximation::from_delta(sun_direction), sun_intensity);
+	approximation
+}
+
+// Bakes colored probe at each position, radiance_at gives radiance arriving at position
+// from direction. Probes are split between rayon threads, every probe uses its own rng
+// seeded with seed ^ index, so the result does not depend on the number of threads
+#[cfg(feature = "parallel")]
+pub fn bake_probe_grid<F>(positions: &[[f32; 3]], radiance_at: F, samples: u32, seed: u64) -> Vec<SHColorApproximation>
+	where F: Fn([f32; 3], Direction) -> [f32; 3] + Sync {
+
+	use rayon::prelude::*;
+
+	positions.par_iter().enumerate().map(|(index, position)| {
+		let mut rng = StdRng::seed_from_u64(seed ^ index as u64);
+		SHColorApproximation::from_function(|x, y, z| radiance_at(*position, Direction::new(x, y, z)), &mut rng, samples)
+	}).collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn bake_matches_brute_force_irradiance() {
+		use crate::spherical_integration::integrate_real_space_hemisphere;
+
+		let radiance = |d: Direction| 1f32 + d.z.max(0f32) + 0.5f32 * d.x;
+		let sh = bake(radiance, ProjectionConfig { samples: 20000, ..ProjectionConfig::default() });
+
+		let mut rng = StdRng::seed_from_u64(1);
+		let mut workspace = SHFuncApproximation::new();
+		for _i in 0..10 {
+			let normal = Direction::generate_random_on_sphere(&mut rng);
+			let irradiance = integrate_real_space_hemisphere(&normal,
+				|x, y, z| normal.dot(&Direction::new(x, y, z)) * radiance(Direction::new(x, y, z)), &mut rng, 20000);
+			let result = sh.eval(normal, &mut workspace);
+			assert!( (result - irradiance).abs() < 0.05 * irradiance, "Result is {0}, expected {1}", result, irradiance);
+		}
+	}
+
+	#[test]
+	fn bake_max_sample_value_prevents_overflow() {
+		let spike = |d: Direction| if d.z > 0.95f32 { f32::MAX } else { 1f32 };
+
+		let uncapped = bake(spike, ProjectionConfig::default());
+		assert!(!uncapped.is_finite());
+
+		let capped = bake(spike, ProjectionConfig { max_sample_value: Some(1e5f32), ..ProjectionConfig::default() });
+		assert!(capped.is_finite(), "Capped projection overflowed: {0:?}", capped);
+	}
+
+	#[test]
+	fn full_visibility_is_constant() {
+		let visibility = bake_visibility_sh(|_x,_y,_z| 1f32, &mut StdRng::seed_from_u64(3), 1000);
+		let constant = SHFuncApproximation::from_function(|_x,_y,_z| 1f32, &mut StdRng::seed_from_u64(3), 1000);
+
+		crate::spherical_harmonics::assert_sh_close(&visibility, &constant, 1e-6);
+	}
+
+	#[test]
+	fn cone_visibility_full() {
+		let mut rng = StdRng::seed_from_u64(0);
+		let sh = bake_cone_visibility_sh(|_x,_y,_z| 1f32, &mut rng, 10000);
+
+		let mut workspace = SHFuncApproximation::new();
+		for _i in 0..20 {
+			let value = sh.eval(Direction::generate_random_on_sphere(&mut rng), &mut workspace);
+			assert!( (value - 1f32).abs() < 0.1, "Result is {0}, expected 1", value);
+		}
+	}
+
+	#[test]
+	fn bent_normal_full_visibility() {
+		let mut rng = StdRng::seed_from_u64(0);
+		let sh = bake_bent_normal_sh(|_x,_y,_z| 1f32, &mut rng, 10000);
+
+		let bent_normal = sh.dominant_direction().unwrap();
+		assert!(bent_normal.z > 0.99, "Bent normal should point along +Z, got {0:?}", bent_normal);
+
+		// Average of reconstruction is 4PI Y00 c0
+		let ao = 4f32 * std::f32::consts::PI * 0.28209479f32 * sh.coefficients()[0];
+		assert!( (ao - 1f32).abs() < 0.05, "Expected no occlusion, got {0}", ao);
+
+		// Blocking half of the hemisphere tilts the bent normal away from it
+		let sh = bake_bent_normal_sh(|x,_y,_z| if x > 0f32 { 0f32 } else { 1f32 }, &mut rng, 10000);
+		assert!(sh.dominant_direction().unwrap().x < -0.1);
+	}
+
+	#[test]
+	fn sun_sky_points_at_sun() {
+		let mut rng = StdRng::seed_from_u64(0);
+		let sun = Direction::normalized(0.3f32, -0.5f32, 0.8f32);
+
+		// Sky is brighter towards zenith, but sun dominates
+		let sh = bake_sun_sky_sh(sun, 20f32, |_x,_y,z| 0.5f32 + 0.25f32 * z, &mut rng, 10000);
+		let dominant = sh.dominant_direction().unwrap();
+		assert!(dominant.angle_to(&sun) < 0.1, "Expected direction close to {0:?}, got {1:?}", sun, dominant);
+	}
+
+	#[test]
+	fn approximations_are_send_and_sync() {
+		fn assert_send_sync<T: Send + Sync>() {}
+		assert_send_sync::<SHFuncApproximation>();
+		assert_send_sync::<crate::spherical_harmonics_color::SHColorApproximation>();
+		assert_send_sync::<crate::probe::SHProbe>();
+		assert_send_sync::<Direction>();
+	}
+
+	#[cfg(feature = "parallel")]
+	#[test]
+	fn probe_grid_uniform_field() {
+		let mut positions = Vec::new();
+		for i in 0..8 {
+			positions.push([(i & 1) as f32, ((i >> 1) & 1) as f32, ((i >> 2) & 1) as f32]);
+		}
+
+		let probes = bake_probe_grid(&positions, |_position, _direction| [1f32, 0.5f32, 2f32], 10000, 7);
+		assert_eq!(probes.len(), 8);
+
+		let mut workspace = SHFuncApproximation::new();
+		for probe in probes.iter() {
+			let color = probe.eval(Direction::new(0.6f32, 0f32, 0.8f32), &mut workspace);
+			for (result, expected) in color.iter().zip([1f32, 0.5f32, 2f32].iter()) {
+				assert!( (result - expected).abs() < 0.1 * expected, "Result is {0}, expected {1}", result, expected);
+			}
+		}
+
+		// Same seed gives the same probes
+		let again = bake_probe_grid(&positions, |_position, _direction| [1f32, 0.5f32, 2f32], 10000, 7);
+		assert_eq!(again[5].channels()[1].coefficients(), probes[5].channels()[1].coefficients());
+	}
+}
@@ -0,0 +1,82 @@
+use cgmath::{Matrix3, Quaternion, Vector3};
+use crate::spherical::Direction;
+use crate::spherical_harmonics::SHFuncApproximation;
+use crate::spherical_harmonics_color::SHColorApproximation;
+
+
+impl From<Direction> for Vector3<f32> {
+	fn from(direction: Direction) -> Vector3<f32> {
+		Vector3::new(direction.x, direction.y, direction.z)
+	}
+}
+
+// Vector does not need to be normalized, but must not be zero
+impl From<Vector3<f32>> for Direction {
+	fn from(vector: Vector3<f32>) -> Direction {
+		Direction::normalized(vector.x, vector.y, vector.z)
+	}
+}
+
+// Row major matrix used by rotate. cgmath matrices store columns
+pub fn matrix_from_cgmath(matrix: &Matrix3<f32>) -> [[f32; 3]; 3] {
+	[
+		[matrix.x.x, matrix.y.x, matrix.z.x],
+		[matrix.x.y, matrix.y.y, matrix.z.y],
+		[matrix.x.z, matrix.y.z, matrix.z.z]
+	]
+}
+
+impl SHFuncApproximation {
+	// Same as rotate, with cgmath rotation matrix
+	pub fn rotate_cgmath(&self, rotation: &Matrix3<f32>) -> SHFuncApproximation {
+		self.rotate(&matrix_from_cgmath(rotation))
+	}
+
+	// Same as rotate_quat, with cgmath unit quaternion
+	pub fn rotate_cgmath_quat(&self, quaternion: Quaternion<f32>) -> SHFuncApproximation {
+		self.rotate_quat([quaternion.v.x, quaternion.v.y, quaternion.v.z, quaternion.s])
+	}
+}
+
+impl SHColorApproximation {
+	// Same as rotate, with cgmath rotation matrix
+	pub fn rotate_cgmath(&self, rotation: &Matrix3<f32>) -> SHColorApproximation {
+		self.rotate(&matrix_from_cgmath(rotation))
+	}
+
+	// Same as rotate_quat, with cgmath unit quaternion
+	pub fn rotate_cgmath_quat(&self, quaternion: Quaternion<f32>) -> SHColorApproximation {
+		self.rotate_quat([quaternion.v.x, quaternion.v.y, quaternion.v.z, quaternion.s])
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use cgmath::{Deg, Rotation3};
+	use rand::{SeedableRng, rngs::StdRng};
+	use crate::spherical_harmonics::assert_sh_close;
+
+	#[test]
+	fn vector_round_trip() {
+		let direction = Direction::normalized(0.3f32, -0.4f32, 0.8f32);
+		let vector: Vector3<f32> = direction.into();
+		let back: Direction = vector.into();
+		assert!(back.angle_to(&direction) < 1e-6, "Result is {0:?}, expected {1:?}", back, direction);
+		assert_eq!([vector.x, vector.y, vector.z], [direction.x, direction.y, direction.z]);
+	}
+
+	#[test]
+	fn rotate_by_cgmath_quaternion() {
+		let mut rng = StdRng::seed_from_u64(0);
+		let sh = SHFuncApproximation::from_function(|x, y, z| 1f32 + x.max(0f32) - y * z, &mut rng, 1000);
+
+		let quaternion = Quaternion::from_axis_angle(Vector3::new(0f32, 0f32, 1f32), Deg(90f32));
+		let rotated = sh.rotate_cgmath_quat(quaternion);
+		let expected = sh.rotate(&[[0f32, -1f32, 0f32], [1f32, 0f32, 0f32], [0f32, 0f32, 1f32]]);
+		assert_sh_close(&rotated, &expected, 1e-5);
+
+		// Matrix of the same rotation gives the same result
+		assert_sh_close(&sh.rotate_cgmath(&Matrix3::from(quaternion)), &expected, 1e-5);
+	}
+}
@@ -0,0 +1,305 @@
+use std::f32::consts::PI;
+use crate::spherical::Direction;
+use crate::spherical_harmonics::SHFuncApproximation;
+use crate::spherical_harmonics_color::SHColorApproximation;
+
+
+// How texels are looked up when sampling the image in some direction
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Filter {
+	Nearest,
+	Bilinear
+}
+
+// Equirectangular (lat-long) RGB image. Rows go from +Z (top) to -Z (bottom),
+// columns go around the azimuth starting at +X
+#[derive(Debug, Clone)]
+pub struct EquirectImage {
+	width: usize,
+	height: usize,
+	texels: Vec<[f32; 3]>
+}
+
+impl EquirectImage {
+	// Texels are stored row by row
+	pub fn new(width: usize, height: usize, texels: Vec<[f32; 3]>) -> EquirectImage {
+		assert!(width > 0 && height > 0, "Image must not be empty");
+		assert_eq!(texels.len(), width * height, "Expected {0}x{1} texels", width, height);
+		EquirectImage { width, height, texels }
+	}
+
+	// Creates image by evaluating the function in the center of each texel
+	pub fn from_function<F>(width: usize, height: usize, func: F) -> EquirectImage
+		where F: Fn(f32, f32, f32) -> [f32; 3] {
+
+		let mut texels = Vec::with_capacity(width * height);
+		for row in 0..height {
+			for column in 0..width {
+				let d = texel_direction(column as f32 + 0.5f32, row as f32 + 0.5f32, width, height);
+				texels.push(func(d.x, d.y, d.z));
+			}
+		}
+		EquirectImage::new(width, height, texels)
+	}
+
+	pub fn width(&self) -> usize {
+		self.width
+	}
+
+	pub fn height(&self) -> usize {
+		self.height
+	}
+
+	pub fn texel(&self, column: usize, row: usize) -> [f32; 3] {
+		self.texels[row * self.width + column]
+	}
+
+	// Looks up the image in direction. Bilinear filtering wraps around in
+	// azimuth and clamps at the poles
+	pub fn sample(&self, direction: Direction, filter: Filter) -> [f32; 3] {
+		let (theta, phi) = direction.to_spherical();
+		let u = phi / (2f32 * PI) * self.width as f32;
+		let v = theta / PI * self.height as f32;
+
+		match filter {
+			Filter::Nearest => {
+				let column = (u as usize).min(self.width - 1);
+				let row = (v as usize).min(self.height - 1);
+				self.texel(column, row)
+			},
+			Filter::Bilinear => {
+				// Texel centers are at half coordinates
+				let u = u - 0.5f32;
+				let v = (v - 0.5f32).clamp(0f32, (self.height - 1) as f32);
+
+				let u0 = u.floor();
+				let v0 = v.floor();
+				let fu = u - u0;
+				let fv = v - v0;
+
+				let column0 = (u0 as i64).rem_euclid(self.width as i64) as usize;
+				let column1 = (column0 + 1) % self.width;
+				let row0 = v0 as usize;
+				let row1 = (row0 + 1).min(self.height - 1);
+
+				let mut color = [0f32; 3];
+				for (i, value) in color.iter_mut().enumerate() {
+					let top = self.texel(column0, row0)[i] * (1f32 - fu) + self.texel(column1, row0)[i] * fu;
+					let bottom = self.texel(column0, row1)[i] * (1f32 - fu) + self.texel(column1, row1)[i] * fu;
+					*value = top * (1f32 - fv) + bottom * fv;
+				}
+				color
+			}
+		}
+	}
+}
+
+// Simple analytic sky, linearly blended in z from horizon color at the nadir to zenith
+// color at the top. Useful as a source for examples and tests
+pub fn gradient_sky(horizon: [f32; 3], zenith: [f32; 3]) -> impl Fn(f32, f32, f32) -> [f32; 3] {
+	move |_x, _y, z| {
+		let t = (z + 1f32) / 2f32;
+		[
+			horizon[0] + (zenith[0] - horizon[0]) * t,
+			horizon[1] + (zenith[1] - horizon[1]) * t,
+			horizon[2] + (zenith[2] - horizon[2]) * t
+		]
+	}
+}
+
+// Procedural high frequency sky for stress tests: 3D value noise in [0, 1] evaluated on
+// the sphere surface scaled by frequency. Lattice values are hashed from seed, so the
+// same seed always gives the same sky. Higher frequency puts more energy in high bands
+pub fn noise_sky(seed: u64, frequency: f32) -> impl Fn(f32, f32, f32) -> f32 {
+	move |x, y, z| {
+		let p = [x * frequency, y * frequency, z * frequency];
+		let cell = [p[0].floor(), p[1].floor(), p[2].floor()];
+
+		// Smoothstep fade gives continuous derivatives across cells
+		let fade = |t: f32| t * t * (3f32 - 2f32 * t);
+		let t = [fade(p[0] - cell[0]), fade(p[1] - cell[1]), fade(p[2] - cell[2])];
+
+		let mut value = 0f32;
+		for corner in 0..8 {
+			let offset = [corner & 1, (corner >> 1) & 1, (corner >> 2) & 1];
+			let mut weight = 1f32;
+			let mut key = [0i64; 3];
+			for axis in 0..3 {
+				key[axis] = cell[axis] as i64 + offset[axis] as i64;
+				weight *= if offset[axis] == 1 { t[axis] } else { 1f32 - t[axis] };
+			}
+			value += weight * lattice_value(seed, key);
+		}
+		value
+	}
+}
+
+// Pseudo random value in [0, 1] for integer lattice point (splitmix64 finalizer)
+fn lattice_value(seed: u64, key: [i64; 3]) -> f32 {
+	let mut hash = seed;
+	for k in key.iter() {
+		hash = (hash ^ (*k as u64)).wrapping_add(0x9E37_79B9_7F4A_7C15);
+		hash = (hash ^ (hash >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+		hash = (hash ^ (hash >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+		hash ^= hash >> 31;
+	}
+	(hash >> 40) as f32 / (1u64 << 24) as f32
+}
+
+// Direction at (possibly fractional) texel coordinates of an equirectangular image
+pub fn texel_direction(column: f32, row: f32, width: usize, height: usize) -> Direction {
+	let theta = row / height as f32 * PI;
+	let phi = column / width as f32 * 2f32 * PI;
+	Direction::from_spherical(theta, phi)
+}
+
+// Projects equirectangular image into SH. We integrate over a lat-long grid with
+// supersampling x supersampling points per texel, weighting each by its solid angle.
+// This is deterministic, and with supersampling the filter decides how we
+// reconstruct the image between texel centers
+pub fn project_equirect(image: &EquirectImage, filter: Filter, supersampling: usize) -> SHColorApproximation {
+	assert!(supersampling > 0, "Need at least one sample per texel");
+
+	// Rows are summed one by one, in the same order as the parallel version
+	let mut approximation = SHColorApproximation::new();
+	for row in 0..image.height * supersampling {
+		approximation.add_in_place(&project_equirect_row(image, filter, supersampling, row));
+	}
+	approximation
+}
+
+// Same as project_equirect, but rows are projected on rayon threads. Partial results
+// are collected and summed in row order, so the result is identical to the serial version
+#[cfg(feature = "parallel")]
+pub fn project_equirect_parallel(image: &EquirectImage, filter: Filter, supersampling: usize) -> SHColorApproximation {
+	use rayon::prelude::*;
+
+	assert!(supersampling > 0, "Need at least one sample per texel");
+
+	let rows: Vec<SHColorApproximation> = (0..image.height * supersampling).into_par_iter()
+		.map(|row| project_equirect_row(image, filter, supersampling, row))
+		.collect();
+
+	let mut approximation = SHColorApproximation::new();
+	for row in rows.iter() {
+		approximation.add_in_place(row);
+	}
+	approximation
+}
+
+// Contribution of one row of the supersampled integration grid
+fn project_equirect_row(image: &EquirectImage, filter: Filter, supersampling: usize, row: usize) -> SHColorApproximation {
+	let width = image.width * supersampling;
+	let height = image.height * supersampling;
+
+	// Exact solid angle of lat-long cell
+	let theta0 = row as f32 / height as f32 * PI;
+	let theta1 = (row + 1) as f32 / height as f32 * PI;
+	let solid_angle = (theta0.cos() - theta1.cos()) * 2f32 * PI / width as f32;
+
+	let mut approximation = SHColorApproximation::new();
+	let mut basis = SHFuncApproximation::new();
+	for column in 0..width {
+		let direction = texel_direction(column as f32 + 0.5f32, row as f32 + 0.5f32, width, height);
+		let color = image.sample(direction, filter);
+
+		basis.from_direction(direction);
+		let weights = [
+			color[0] * solid_angle / (4f32 * PI),
+			color[1] * solid_angle / (4f32 * PI),
+			color[2] * solid_angle / (4f32 * PI)
+		];
+		approximation.add_scaled_basis(&basis, weights);
+	}
+	approximation
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use rand::{SeedableRng, rngs::StdRng};
+
+	#[test]
+	fn gradient_sky_brighter_up() {
+		let mut rng = StdRng::seed_from_u64(0);
+		let sky = gradient_sky([0.1f32, 0.2f32, 0.3f32], [0.5f32, 0.8f32, 1f32]);
+		assert_eq!(sky(0f32, 0f32, 1f32), [0.5f32, 0.8f32, 1f32]);
+
+		let sh = SHColorApproximation::from_function(sky, &mut rng, 1000);
+		for channel in sh.channels().iter() {
+			assert!(channel.coefficients()[2] > 0f32, "Z coefficient {0} should be positive", channel.coefficients()[2]);
+		}
+	}
+
+	#[test]
+	fn noise_sky_has_high_bands() {
+		let mut rng = StdRng::seed_from_u64(0);
+		let sky = noise_sky(3, 4f32);
+		assert_eq!(sky(0.6f32, 0f32, 0.8f32), noise_sky(3, 4f32)(0.6f32, 0f32, 0.8f32));
+
+		let sh = SHFuncApproximation::from_function_with_order(3, &sky, &mut rng, 20000);
+		let energies = sh.band_energies();
+		let directional: f32 = energies[1..].iter().sum();
+		assert!(energies[2] > 0.2 * directional, "Band 2 has only {0} of {1}", energies[2], directional);
+		assert!(energies[3] > 0.1 * directional, "Band 3 has only {0} of {1}", energies[3], directional);
+	}
+
+	#[test]
+	fn constant_image_projection() {
+		let image = EquirectImage::from_function(16, 8, |_x,_y,_z| [1f32, 2f32, 3f32]);
+		let sh = project_equirect(&image, Filter::Bilinear, 4);
+
+		let mut workspace = SHFuncApproximation::new();
+		let color = sh.eval(Direction::new(0f32, 0f32, 1f32), &mut workspace);
+		for (result, expected) in color.iter().zip([1f32, 2f32, 3f32].iter()) {
+			assert!( (result - expected).abs() < 0.02, "Result is {0}, expected {1}", result, expected);
+		}
+	}
+
+	#[test]
+	fn bilinear_reduces_aliasing() {
+		// Horizontal gradient, ramp in azimuth up to the opposite side and back down, with
+		// the turning points at texel centers of 8 columns. Bilinear reproduces the piecewise
+		// linear ramp exactly, nearest turns it into steps
+		let ramp = |x: f32, y: f32| {
+			let mut phi = y.atan2(x) - PI / 8f32;
+			if phi < -PI {
+				phi += 2f32 * PI;
+			}
+			phi.abs() / PI
+		};
+		let image = EquirectImage::from_function(8, 4, |x,y,_z| { let v = ramp(x, y); [v, v, v] });
+
+		// SH function 3 is -0.4886 sin(theta) cos(phi). Integral of the ramp times
+		// cos(phi) is -4/PI cos(PI/8) and of sin(theta)^2 is PI/2, so c = 0.4886 cos(PI/8) / 2PI
+		let expected = 0.48860251f32 * (PI / 8f32).cos() / (2f32 * PI);
+		let nearest = project_equirect(&image, Filter::Nearest, 8).channels()[0].coefficients()[3];
+		let bilinear = project_equirect(&image, Filter::Bilinear, 8).channels()[0].coefficients()[3];
+		assert!( (bilinear - expected).abs() < (nearest - expected).abs(),
+			"Bilinear {0} should be closer to {1} than nearest {2}", bilinear, expected, nearest);
+
+		// Lookups in between texel centers follow the ramp more closely as well
+		let mut rng = StdRng::seed_from_u64(0);
+		let mut nearest_error = 0f32;
+		let mut bilinear_error = 0f32;
+		for _i in 0..1000 {
+			let d = Direction::generate_random_on_sphere(&mut rng);
+			let value = ramp(d.x, d.y);
+			nearest_error += (image.sample(d, Filter::Nearest)[0] - value).powi(2);
+			bilinear_error += (image.sample(d, Filter::Bilinear)[0] - value).powi(2);
+		}
+		assert!(bilinear_error < nearest_error, "Bilinear error {0} should be below nearest {1}", bilinear_error, nearest_error);
+	}
+
+	#[cfg(feature = "parallel")]
+	#[test]
+	fn parallel_projection_matches_serial() {
+		let image = EquirectImage::from_function(32, 16, |x,y,z| [x*y + 1f32, z, 0.5f32 * x]);
+
+		let serial = project_equirect(&image, Filter::Bilinear, 2);
+		let parallel = project_equirect_parallel(&image, Filter::Bilinear, 2);
+		for (a, b) in serial.channels().iter().zip(parallel.channels().iter()) {
+			assert_eq!(a.coefficients(), b.coefficients());
+		}
+	}
+}
@@ -0,0 +1,34 @@
+use std::fmt;
+
+
+// Errors of fallible operations in this crate
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+	// Input has different length than expected
+	InvalidLength { expected: usize, actual: usize },
+	// Number of coefficients is not (order+1)^2 for any order
+	InvalidCoefficientCount(usize),
+	// Linear system of a fit has no unique solution, e.g. samples do not cover the sphere
+	SingularSystem,
+	// Too few samples to estimate the requested coefficients
+	InsufficientSamples { required: u32, actual: u32 }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			Error::InvalidLength { expected, actual } =>
+				write!(f, "Invalid input length, expected {0}, got {1}", expected, actual),
+			Error::InvalidCoefficientCount(count) =>
+				write!(f, "{0} coefficients do not form complete SH bands", count),
+			Error::SingularSystem =>
+				write!(f, "Linear system is singular, samples do not determine the fit"),
+			Error::InsufficientSamples { required, actual } =>
+				write!(f, "Insufficient samples, required at least {0}, got {1}", required, actual)
+		}
+	}
+}
+
+impl std::error::Error for Error {}
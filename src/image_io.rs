@@ -0,0 +1,90 @@
+use std::io::Write;
+
+// Header and line offset table of uncompressed scanline OpenEXR image with 32-bit float
+// RGB channels. Uncompressed scanlines have fixed size, so offsets are known up front
+// and the image can be streamed: follow with write_exr_scanline for rows 0 to height-1
+pub fn write_exr_header<W: Write>(writer: &mut W, width: usize, height: usize) -> std::io::Result<()> {
+	assert!(width > 0 && height > 0, "Image must not be empty");
+
+	let mut header = vec![0x76, 0x2F, 0x31, 0x01, 2, 0, 0, 0];
+
+	// Channels must be sorted by name, 2 is FLOAT pixel type, then linear flag,
+	// reserved bytes and x, y sampling
+	let mut channels = Vec::new();
+	for name in [b'B', b'G', b'R'].iter() {
+		channels.extend_from_slice(&[*name, 0]);
+		channels.extend_from_slice(&2i32.to_le_bytes());
+		channels.extend_from_slice(&[0, 0, 0, 0]);
+		channels.extend_from_slice(&1i32.to_le_bytes());
+		channels.extend_from_slice(&1i32.to_le_bytes());
+	}
+	channels.push(0);
+	write_exr_attribute(&mut header, "channels", "chlist", &channels);
+	write_exr_attribute(&mut header, "compression", "compression", &[0]);
+
+	let mut window = Vec::new();
+	for value in [0i32, 0i32, width as i32 - 1, height as i32 - 1].iter() {
+		window.extend_from_slice(&value.to_le_bytes());
+	}
+	write_exr_attribute(&mut header, "dataWindow", "box2i", &window);
+	write_exr_attribute(&mut header, "displayWindow", "box2i", &window);
+	write_exr_attribute(&mut header, "lineOrder", "lineOrder", &[0]);
+	write_exr_attribute(&mut header, "pixelAspectRatio", "float", &1f32.to_le_bytes());
+	write_exr_attribute(&mut header, "screenWindowCenter", "v2f", &[0; 8]);
+	write_exr_attribute(&mut header, "screenWindowWidth", "float", &1f32.to_le_bytes());
+	header.push(0);
+
+	// Offsets of scanline blocks in the file, each is row, data size and the data
+	let block_size = 8 + width * 3 * 4;
+	let first = header.len() + height * 8;
+	for row in 0..height {
+		header.extend_from_slice(&((first + row * block_size) as u64).to_le_bytes());
+	}
+	writer.write_all(&header)
+}
+
+// Writes one row of image started with write_exr_header, rows must come in order from 0
+pub fn write_exr_scanline<W: Write>(writer: &mut W, row: usize, rgb: &[[f32; 3]]) -> std::io::Result<()> {
+	let mut block = Vec::with_capacity(8 + rgb.len() * 3 * 4);
+	block.extend_from_slice(&(row as i32).to_le_bytes());
+	block.extend_from_slice(&((rgb.len() * 3 * 4) as i32).to_le_bytes());
+	for channel in [2, 1, 0].iter() {
+		for texel in rgb.iter() {
+			block.extend_from_slice(&texel[*channel].to_le_bytes());
+		}
+	}
+	writer.write_all(&block)
+}
+
+// Attribute is name, type name, size and value
+fn write_exr_attribute(header: &mut Vec<u8>, name: &str, type_name: &str, value: &[u8]) {
+	header.extend_from_slice(name.as_bytes());
+	header.push(0);
+	header.extend_from_slice(type_name.as_bytes());
+	header.push(0);
+	header.extend_from_slice(&(value.len() as i32).to_le_bytes());
+	header.extend_from_slice(value);
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn exr_offsets_point_at_scanlines() {
+		let mut bytes = Vec::new();
+		write_exr_header(&mut bytes, 3, 2).unwrap();
+		let table = bytes.len() - 2 * 8;
+		write_exr_scanline(&mut bytes, 0, &[[1f32, 2f32, 3f32]; 3]).unwrap();
+		write_exr_scanline(&mut bytes, 1, &[[4f32, 5f32, 6f32]; 3]).unwrap();
+
+		let mut offset = [0u8; 8];
+		offset.copy_from_slice(&bytes[table + 8..table + 16]);
+		let second = u64::from_le_bytes(offset) as usize;
+		assert_eq!(&bytes[second..second + 4], &1i32.to_le_bytes());
+		assert_eq!(second + 8 + 3 * 3 * 4, bytes.len());
+
+		// Channels are stored B, G, R
+		assert_eq!(&bytes[second + 8..second + 12], &6f32.to_le_bytes());
+	}
+}
@@ -0,0 +1,14 @@
+pub mod spherical_harmonics;
+pub mod spherical_harmonics_color;
+pub mod spherical_gaussian;
+pub mod spherical_integration;
+pub mod spherical;
+pub mod sampler;
+pub mod probe;
+pub mod bake;
+pub mod envmap;
+pub mod error;
+#[cfg(feature = "image-io")]
+pub mod image_io;
+#[cfg(feature = "cgmath")]
+pub mod cgmath_interop;
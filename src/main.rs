@@ -1,22 +1,21 @@
-mod spherical_harmonics;
-mod spherical_integration;
-mod spherical;
-
-use spherical::Direction;
-use spherical_harmonics::SHFuncApproximation;
-use spherical_integration::integrate_real_space_hemisphere;
+use light_probles_calculation::spherical::Direction;
+use light_probles_calculation::spherical_harmonics::SHFuncApproximation;
+use light_probles_calculation::spherical_integration::integrate_real_space_hemisphere;
+use light_probles_calculation::envmap::gradient_sky;
+use light_probles_calculation::bake::{bake, ProjectionConfig};
 
 fn main() {
-	let mut rng = rand::thread_rng();
-
-	// Here, we would probably need to load a cubemap
-	let cubemap = |x,y,z| (x*y + 0.5f32*z + 0.25f32 * x + 0.05f32 * x * y) * (1f32 - x*x + y);
+	// Here, we would probably need to load a cubemap, we use luminance of a simple sky
+	let sky = gradient_sky([0.05f32, 0.05f32, 0.1f32], [0.4f32, 0.6f32, 1f32]);
+	let cubemap = |x,y,z| {
+		let color = sky(x,y,z);
+		0.2126f32 * color[0] + 0.7152f32 * color[1] + 0.0722f32 * color[2]
+	};
 
 	// We are using Lambertian cosine lightning that only depends on the
 	// normal of the surface, not the out camera direction. We can therefore
 	// precalculate the integral for normal direction
-	let lightning_per_normal = |nx, ny, nz| {
-		let normal = Direction::new(nx, ny, nz);
+	let lightning_per_normal = |normal: Direction| {
 		let lightning_function = |x,y,z| {
 			let direction = Direction::new(x,y,z);
 			let cosine = normal.dot(&direction);
@@ -33,9 +32,9 @@ fn main() {
 		integrate_real_space_hemisphere(&normal, lightning_function, &mut internal_rng, 5000)
 	};
 
-	// Doing only 1000 samples should be sufficient as we only calculate 9 coefficients from.
-	// TODO: parallelize this call
-	let sh = SHFuncApproximation::from_function(lightning_per_normal, &mut rng, 1000);
+	// Radiance is projected and convolved with the cosine lobe in SH space, which gives
+	// the same irradiance per normal without integrating for every sample
+	let sh = bake(|d: Direction| cubemap(d.x, d.y, d.z), ProjectionConfig::default());
     println!("Resulting coefficients are {:?}\n", sh);
 
     let compare_values = |dir| {
@@ -47,9 +46,9 @@ fn main() {
 
     	// TODO: we are recalculating the integral here, possibly with different value. This
     	// may bring more difference
-    	let direct_value = lightning_per_normal(dir.x, dir.y, dir.z);
+    	let direct_value = lightning_per_normal(dir);
 
-    	print!("Compare values for '{0:?}': SH {1}, direct {2}\n", dir, sh_value, direct_value);
+    	println!("Compare values for '{0:?}': SH {1}, direct {2}", dir, sh_value, direct_value);
     };
 
 
@@ -0,0 +1,186 @@
+use crate::spherical::Direction;
+use crate::spherical_harmonics_color::SHColorApproximation;
+
+
+// Light probe, SH approximation of lighting baked at a position in the world
+#[derive(Debug, Clone)]
+pub struct SHProbe {
+	pub position: [f32; 3],
+	pub sh: SHColorApproximation
+}
+
+impl SHProbe {
+	// Blends probes with inverse squared distance weights to get the lighting at
+	// position. Probe exactly at position is returned as is
+	pub fn sample(position: [f32; 3], probes: &[SHProbe]) -> SHColorApproximation {
+		SHProbe::sample_within(position, f32::INFINITY, probes)
+	}
+
+	// Same as sample, but only probes within radius of position are blended. Weights are
+	// inverse squared distance minus 1/radius², so a probe fades out smoothly as it
+	// reaches radius and distant probes never leak in. If no probe is within radius the
+	// nearest one is returned
+	pub fn sample_within(position: [f32; 3], radius: f32, probes: &[SHProbe]) -> SHColorApproximation {
+		assert!(!probes.is_empty(), "Need at least one probe to sample");
+		assert!(radius > 0f32, "Radius must be positive, is {0}", radius);
+
+		let radius2 = radius * radius;
+		let mut nearest = (0, f32::INFINITY);
+		let mut weighted = Vec::new();
+		for (i, probe) in probes.iter().enumerate() {
+			let distance2 = distance_squared(position, probe.position);
+			if distance2 < 1e-12f32 {
+				return probe.sh.clone();
+			}
+			if distance2 < nearest.1 {
+				nearest = (i, distance2);
+			}
+			if distance2 < radius2 {
+				weighted.push((probe, 1f32 / distance2 - 1f32 / radius2));
+			}
+		}
+
+		if weighted.is_empty() {
+			return probes[nearest.0].sh.clone();
+		}
+		blend(&weighted)
+	}
+
+	// Same as sample, but each probe weight is also multiplied by occlusion (0 when the
+	// probe is not visible from position, 1 when fully visible) and by a smooth backface
+	// term that prefers probes in front of the surface with normal. This reduces light
+	// leaking through walls. If all probes are rejected, we fall back to plain sample
+	pub fn sample_with_visibility<F>(position: [f32; 3], normal: Direction, probes: &[SHProbe], occlusion: F) -> SHColorApproximation
+		where F: Fn(&SHProbe) -> f32 {
+
+		assert!(!probes.is_empty(), "Need at least one probe to sample");
+
+		let weighted: Vec<(&SHProbe, f32)> = probes.iter().map(|probe| {
+			let distance2 = distance_squared(position, probe.position);
+			let backface = if distance2 < 1e-12f32 {
+				1f32
+			} else {
+				let towards = Direction::normalized(
+					probe.position[0] - position[0], probe.position[1] - position[1], probe.position[2] - position[2]);
+				let wrap = (towards.dot(&normal) + 1f32) / 2f32;
+				wrap * wrap
+			};
+			(probe, occlusion(probe) * backface / distance2.max(1e-12f32))
+		}).collect();
+
+		if weighted.iter().map(|(_, weight)| weight).sum::<f32>() <= 0f32 {
+			return SHProbe::sample(position, probes);
+		}
+		blend(&weighted)
+	}
+}
+
+// Sum of probes weighted by normalized weights
+fn blend(weighted: &[(&SHProbe, f32)]) -> SHColorApproximation {
+	let total: f32 = weighted.iter().map(|(_, weight)| weight).sum();
+	let mut result = SHColorApproximation::with_order(weighted[0].0.sh.order());
+	for (probe, weight) in weighted {
+		let mut contribution = probe.sh.clone();
+		contribution.mul_in_place(weight / total);
+		result.add_in_place(&contribution);
+	}
+	result
+}
+
+fn distance_squared(a: [f32; 3], b: [f32; 3]) -> f32 {
+	let dx = a[0] - b[0];
+	let dy = a[1] - b[1];
+	let dz = a[2] - b[2];
+	dx*dx + dy*dy + dz*dz
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use rand::{SeedableRng, rngs::StdRng};
+	use crate::spherical::Direction;
+	use crate::spherical_harmonics::SHFuncApproximation;
+
+	#[test]
+	fn sample_halfway_is_average() {
+		let mut rng = StdRng::seed_from_u64(0);
+		let a = SHProbe {
+			position: [0f32, 0f32, 0f32],
+			sh: SHColorApproximation::from_function(|x,_y,_z| [1f32 + x, 0f32, 0.5f32], &mut rng, 1000)
+		};
+		let b = SHProbe {
+			position: [2f32, 0f32, 0f32],
+			sh: SHColorApproximation::from_function(|_x,y,_z| [0f32, 1f32 + y, 0.5f32], &mut rng, 1000)
+		};
+
+		let probes = [a, b];
+		let sampled = SHProbe::sample([1f32, 0f32, 0f32], &probes);
+
+		let mut workspace = SHFuncApproximation::new();
+		let direction = Direction::new(1f32/2f32.sqrt(), 1f32/2f32.sqrt(), 0f32);
+		let result = sampled.eval(direction, &mut workspace);
+		let color_a = probes[0].sh.eval(direction, &mut workspace);
+		let color_b = probes[1].sh.eval(direction, &mut workspace);
+		for i in 0..3 {
+			let expected = (color_a[i] + color_b[i]) / 2f32;
+			assert!( (result[i] - expected).abs() < 1e-5, "Result is {0}, expected {1}", result[i], expected);
+		}
+
+		// Sampling at probe position returns the probe
+		let at_probe = SHProbe::sample([0f32, 0f32, 0f32], &probes).eval(direction, &mut workspace);
+		assert_eq!(at_probe, color_a);
+	}
+
+	#[test]
+	fn sample_within_ignores_distant_probes() {
+		let mut rng = StdRng::seed_from_u64(0);
+		let probe = |position, color: [f32; 3], rng: &mut StdRng| SHProbe {
+			position,
+			sh: SHColorApproximation::from_function(|_x,_y,_z| color, rng, 1000)
+		};
+		let a = probe([0f32, 0f32, 0f32], [1f32, 0f32, 0f32], &mut rng);
+		let b = probe([2f32, 0f32, 0f32], [0f32, 1f32, 0f32], &mut rng);
+		let distant = probe([100f32, 0f32, 0f32], [0f32, 0f32, 50f32], &mut rng);
+
+		let near_probes = [a.clone(), b.clone()];
+		let all_probes = [a, b, distant];
+		let mut workspace = SHFuncApproximation::new();
+		let direction = Direction::new(0f32, 0f32, 1f32);
+		let expected = SHProbe::sample_within([1f32, 0f32, 0f32], 4f32, &near_probes).eval(direction, &mut workspace);
+		let result = SHProbe::sample_within([1f32, 0f32, 0f32], 4f32, &all_probes).eval(direction, &mut workspace);
+		assert_eq!(result, expected);
+
+		// Equal distance, so still the average of the two near probes
+		let average = (near_probes[0].sh.eval(direction, &mut workspace)[0] + near_probes[1].sh.eval(direction, &mut workspace)[0]) / 2f32;
+		assert!( (result[0] - average).abs() < 1e-5, "Result is {0}, expected {1}", result[0], average);
+
+		// Nothing within radius picks the nearest probe
+		let far = SHProbe::sample_within([60f32, 0f32, 0f32], 4f32, &all_probes).eval(direction, &mut workspace);
+		assert_eq!(far, all_probes[2].sh.eval(direction, &mut workspace));
+	}
+
+	#[test]
+	fn sample_occluded_probe_ignored() {
+		let mut rng = StdRng::seed_from_u64(0);
+		let visible = SHProbe {
+			position: [0f32, 0f32, 1f32],
+			sh: SHColorApproximation::from_function(|x,_y,_z| [1f32 + x, 0.5f32, 0f32], &mut rng, 1000)
+		};
+		let occluded = SHProbe {
+			position: [0f32, 0f32, -1f32],
+			sh: SHColorApproximation::from_function(|_x,_y,_z| [0f32, 0f32, 10f32], &mut rng, 1000)
+		};
+
+		let probes = [visible, occluded];
+		let normal = Direction::new(1f32, 0f32, 0f32);
+		let sampled = SHProbe::sample_with_visibility([0f32, 0f32, 0f32], normal, &probes,
+			|probe| if probe.position[2] < 0f32 { 0f32 } else { 1f32 });
+
+		let mut workspace = SHFuncApproximation::new();
+		let result = sampled.eval(normal, &mut workspace);
+		let expected = probes[0].sh.eval(normal, &mut workspace);
+		for i in 0..3 {
+			assert!( (result[i] - expected[i]).abs() < 1e-5, "Result is {0}, expected {1}", result[i], expected[i]);
+		}
+	}
+}
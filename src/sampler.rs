@@ -0,0 +1,150 @@
+use rand::{Rng, RngCore};
+use std::f32::consts::PI;
+use crate::spherical::Direction;
+use crate::spherical_harmonics::SHFuncApproximation;
+
+// Strategy for generating directions for Monte Carlo estimates. Every direction comes
+// with its probability density (with respect to solid angle), so estimates can weight
+// the samples by 1 / pdf. Takes dyn RngCore so samplers can be used as trait objects
+pub trait SphereSampler {
+	fn next_direction(&mut self, rng: &mut dyn RngCore) -> (Direction, f32);
+
+	// Probability density of generating direction, needed to combine samplers
+	fn pdf(&self, direction: &Direction) -> f32;
+}
+
+// Uniform directions on the whole sphere, pdf is 1/4PI
+#[derive(Debug, Clone, Copy, Default)]
+pub struct UniformSampler;
+
+impl SphereSampler for UniformSampler {
+	fn next_direction(&mut self, mut rng: &mut dyn RngCore) -> (Direction, f32) {
+		(Direction::generate_random_on_sphere(&mut rng), 1f32 / (4f32 * PI))
+	}
+
+	fn pdf(&self, _direction: &Direction) -> f32 {
+		1f32 / (4f32 * PI)
+	}
+}
+
+// Directions on hemisphere around normal, distributed by cos(theta). Pdf is
+// cos(theta)/PI, so it must only be used for functions that are zero below the horizon
+#[derive(Debug, Clone, Copy)]
+pub struct CosineWeightedSampler {
+	pub normal: Direction
+}
+
+impl SphereSampler for CosineWeightedSampler {
+	fn next_direction(&mut self, rng: &mut dyn RngCore) -> (Direction, f32) {
+		// Uniform point on disk projected up to the hemisphere (Malley's method)
+		let radius = rng.gen::<f32>().sqrt();
+		let phi = 2f32 * PI * rng.gen::<f32>();
+		let cosine = (1f32 - radius * radius).max(0f32).sqrt();
+
+		let (tangent, bitangent) = self.normal.tangent_basis();
+		let u = radius * phi.cos();
+		let v = radius * phi.sin();
+		let n = &self.normal;
+		let direction = Direction::normalized(
+			u*tangent.x + v*bitangent.x + cosine*n.x,
+			u*tangent.y + v*bitangent.y + cosine*n.y,
+			u*tangent.z + v*bitangent.z + cosine*n.z);
+		(direction, cosine / PI)
+	}
+
+	fn pdf(&self, direction: &Direction) -> f32 {
+		direction.dot(&self.normal).max(0f32) / PI
+	}
+}
+
+// Jittered stratification of the sphere into resolution x resolution cells of equal area
+// (uniform in z and azimuth). Consecutive directions visit the cells in turn, so any
+// multiple of resolution^2 samples covers the sphere evenly. Pdf is 1/4PI
+#[derive(Debug, Clone, Copy)]
+pub struct StratifiedSampler {
+	resolution: u32,
+	index: u32
+}
+
+impl StratifiedSampler {
+	pub fn new(resolution: u32) -> StratifiedSampler {
+		assert!(resolution > 0, "Resolution must be positive");
+		StratifiedSampler { resolution, index: 0 }
+	}
+}
+
+impl SphereSampler for StratifiedSampler {
+	fn next_direction(&mut self, rng: &mut dyn RngCore) -> (Direction, f32) {
+		let n = self.resolution;
+		let row = self.index / n;
+		let col = self.index % n;
+		self.index = (self.index + 1) % (n * n);
+
+		let z = 1f32 - 2f32 * (row as f32 + rng.gen::<f32>()) / n as f32;
+		let phi = 2f32 * PI * (col as f32 + rng.gen::<f32>()) / n as f32;
+		let sine = (1f32 - z * z).max(0f32).sqrt();
+		(Direction::normalized(sine * phi.cos(), sine * phi.sin(), z), 1f32 / (4f32 * PI))
+	}
+
+	// Averaged over a full cycle of cells, every direction is equally likely
+	fn pdf(&self, _direction: &Direction) -> f32 {
+		1f32 / (4f32 * PI)
+	}
+}
+
+// Signal to noise ratio of projecting func with sampler, for comparing sampling strategies.
+// We project trials times with count samples and compute per coefficient mean and variance
+// across trials. Result is RMS of the means divided by RMS of the standard deviations
+pub fn projection_snr<F>(func: F, sampler: &mut dyn SphereSampler, rng: &mut dyn RngCore, count: u32, trials: u32) -> f32
+	where F: Fn(f32, f32, f32) -> f32 {
+
+	assert!(trials > 1, "Need at least two trials to estimate variance");
+	let projections: Vec<SHFuncApproximation> = (0..trials)
+		.map(|_i| SHFuncApproximation::from_function_with_sampler(&func, sampler, rng, count))
+		.collect();
+
+	let mut signal = 0f32;
+	let mut noise = 0f32;
+	for i in 0..projections[0].coefficients().len() {
+		let mean = projections.iter().map(|p| p.coefficients()[i]).sum::<f32>() / trials as f32;
+		let variance = projections.iter().map(|p| (p.coefficients()[i] - mean).powi(2)).sum::<f32>() / (trials - 1) as f32;
+		signal += mean * mean;
+		noise += variance;
+	}
+	(signal / noise).sqrt()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use rand::{SeedableRng, rngs::StdRng};
+
+	#[test]
+	fn samplers_match_pdf() {
+		let mut rng = StdRng::seed_from_u64(0);
+		let normal = Direction::normalized(1f32, 1f32, 0f32);
+		let mut samplers: Vec<Box<dyn SphereSampler>> = vec![Box::new(UniformSampler),
+			Box::new(CosineWeightedSampler { normal }), Box::new(StratifiedSampler::new(8))];
+
+		// Integral of max(cos, 0) over the sphere is PI for every sampler
+		for sampler in samplers.iter_mut() {
+			let mut sum = 0f32;
+			for _i in 0..6400 {
+				let (d, pdf) = sampler.next_direction(&mut rng);
+				sum += d.dot(&normal).max(0f32) / pdf;
+			}
+			let result = sum / 6400f32;
+			assert!( (result - PI).abs() < 0.15, "Result is {0}, expected {1}", result, PI);
+		}
+	}
+
+	#[test]
+	fn stratified_snr_is_higher() {
+		let mut rng = StdRng::seed_from_u64(0);
+		let func = |x: f32, _y: f32, z: f32| 1f32 + x + z * z;
+
+		let uniform = projection_snr(func, &mut UniformSampler, &mut rng, 1024, 20);
+		let stratified = projection_snr(func, &mut StratifiedSampler::new(32), &mut rng, 1024, 20);
+		assert!(stratified > uniform, "Stratified SNR {0} is not above uniform {1}", stratified, uniform);
+	}
+}
@@ -1,4 +1,5 @@
 use rand::Rng;
+use std::f32::consts::PI;
 
 // A direction, represented as floats. Invariant is length == 1
 #[derive(Debug, Copy, Clone)]
@@ -10,8 +11,21 @@ pub struct Direction {
 
 impl Direction {
 	pub fn new(x: f32, y: f32, z: f32) -> Direction {
-		assert!( (x*x + y*y + z*z - 1f32).abs() < 1e-5f32, "Direction is not normalized");
-		Direction {x: x, y: y, z: z }
+		Direction::new_with_tolerance(x, y, z, 1e-5f32)
+	}
+
+	// Same as new, but accepts squared length off from 1 by less than eps. Useful for
+	// directions from f32 cross products or quantized normals
+	pub fn new_with_tolerance(x: f32, y: f32, z: f32, eps: f32) -> Direction {
+		assert!( (x*x + y*y + z*z - 1f32).abs() < eps, "Direction is not normalized");
+		Direction { x, y, z }
+	}
+
+	// Creates direction from arbitrary non-zero vector by normalizing it
+	pub fn normalized(x: f32, y: f32, z: f32) -> Direction {
+		let r = (x*x + y*y + z*z).sqrt();
+		assert!(r > 0f32, "Cannot create direction from zero vector");
+		Direction { x: x/r, y: y/r, z: z/r }
 	}
 
 	// We use rejection method for generation. Generate in cube, and retry
@@ -29,8 +43,7 @@ impl Direction {
 				continue;
 			}
 
-			let r = r2.sqrt();
-			return Direction {x: x/r, y: y/r, z: z/r};
+			return Direction::normalized(x, y, z);
 		}
 	}
 
@@ -38,6 +51,143 @@ impl Direction {
 		self.x*other.x + self.y*other.y + self.z*other.z
 	}
 
+	// Creates direction from polar angle theta (measured from +Z) and azimuth
+	// phi (measured from +X towards +Y)
+	pub fn from_spherical(theta: f32, phi: f32) -> Direction {
+		Direction::normalized(theta.sin() * phi.cos(), theta.sin() * phi.sin(), theta.cos())
+	}
+
+	// Inverse of from_spherical, returns (theta, phi) with phi in [0, 2PI)
+	pub fn to_spherical(&self) -> (f32, f32) {
+		let theta = self.z.clamp(-1f32, 1f32).acos();
+		let mut phi = self.y.atan2(self.x);
+		if phi < 0f32 {
+			phi += 2f32 * PI;
+		}
+		(theta, phi)
+	}
+
+	// True if all components differ by at most eps
+	pub fn approx_eq(&self, other: &Direction, eps: f32) -> bool {
+		(self.x - other.x).abs() <= eps && (self.y - other.y).abs() <= eps && (self.z - other.z).abs() <= eps
+	}
+
+	// Snaps components to a grid with given number of cells per unit, to be used as
+	// a hash map key (f32 has neither Eq nor Hash). Directions closer than a cell
+	// usually share the key, except when they lie across a cell boundary
+	pub fn quantized_key(&self, grid: u32) -> (i32, i32, i32) {
+		let scale = grid as f32;
+		((self.x * scale).round() as i32, (self.y * scale).round() as i32, (self.z * scale).round() as i32)
+	}
+
+	// Angle in radians between two directions
+	pub fn angle_to(&self, other: &Direction) -> f32 {
+		self.dot(other).clamp(-1f32, 1f32).acos()
+	}
+
+	// Spherical interpolation along the great circle, t = 0 gives self, t = 1 other.
+	// For nearly equal directions sin of the angle goes to zero, so we fall back
+	// to linear interpolation
+	pub fn slerp(&self, other: &Direction, t: f32) -> Direction {
+		let angle = self.angle_to(other);
+		let sine = angle.sin();
+
+		let (a, b) = if sine < 1e-4f32 {
+			(1f32 - t, t)
+		} else {
+			(((1f32 - t) * angle).sin() / sine, (t * angle).sin() / sine)
+		};
+
+		Direction::normalized(a*self.x + b*other.x, a*self.y + b*other.y, a*self.z + b*other.z)
+	}
+
+	// Rotates direction around axis by angle (in radians, counter-clockwise when
+	// looking against the axis) using Rodrigues' formula
+	pub fn rotate_axis_angle(&self, axis: Direction, angle: f32) -> Direction {
+		let cosine = angle.cos();
+		let sine = angle.sin();
+		let d = axis.dot(self) * (1f32 - cosine);
+
+		// Cross product axis x self
+		let cx = axis.y*self.z - axis.z*self.y;
+		let cy = axis.z*self.x - axis.x*self.z;
+		let cz = axis.x*self.y - axis.y*self.x;
+
+		Direction::normalized(
+			self.x*cosine + cx*sine + axis.x*d,
+			self.y*cosine + cy*sine + axis.y*d,
+			self.z*cosine + cz*sine + axis.z*d)
+	}
+
+	// Reflects the direction about the normal, d - 2(d.n)n. We renormalize
+	// the result so rounding errors do not accumulate
+	pub fn reflect(&self, normal: &Direction) -> Direction {
+		let d = 2f32 * self.dot(normal);
+		Direction::normalized(self.x - d*normal.x, self.y - d*normal.y, self.z - d*normal.z)
+	}
+
+	// Refracts the direction (pointing towards the surface) through the surface
+	// with given normal, eta is ratio of indices of refraction. Returns None
+	// on total internal reflection
+	pub fn refract(&self, normal: &Direction, eta: f32) -> Option<Direction> {
+		let cosine = self.dot(normal);
+		let k = 1f32 - eta*eta*(1f32 - cosine*cosine);
+		if k < 0f32 {
+			return None;
+		}
+
+		let d = eta*cosine + k.sqrt();
+		Some(Direction::normalized(eta*self.x - d*normal.x, eta*self.y - d*normal.y, eta*self.z - d*normal.z))
+	}
+
+	// Projection of self onto the plane perpendicular to normal, self - dot(self, n) n.
+	// Not renormalized, it is zero when self is parallel to normal
+	pub fn tangent_component(&self, normal: &Direction) -> [f32; 3] {
+		let d = self.dot(normal);
+		[self.x - d * normal.x, self.y - d * normal.y, self.z - d * normal.z]
+	}
+
+	// Two directions that form orthonormal basis together with self. We use the
+	// branchless construction from Duff et al. 2017, which is robust around the poles
+	pub fn tangent_basis(&self) -> (Direction, Direction) {
+		let sign = 1f32.copysign(self.z);
+		let a = -1f32 / (sign + self.z);
+		let b = self.x * self.y * a;
+
+		let tangent = Direction::normalized(1f32 + sign * self.x * self.x * a, sign * b, -sign * self.x);
+		let bitangent = Direction::normalized(b, sign + self.y * self.y * a, -self.y);
+		(tangent, bitangent)
+	}
+
+	// Uniformly samples ring around axis with polar angles between inner and
+	// outer angle (in radians). Uniform on sphere means uniform in cos(theta)
+	pub fn generate_random_in_ring<R>(axis: &Direction, inner_angle: f32, outer_angle: f32, rng: &mut R) -> Direction
+		where R : Rng {
+
+		let (tangent, bitangent) = axis.tangent_basis();
+
+		let cos_inner = inner_angle.cos();
+		let cos_outer = outer_angle.cos();
+		let cosine = cos_outer + (cos_inner - cos_outer) * rng.gen::<f32>();
+		let sine = (1f32 - cosine * cosine).max(0f32).sqrt();
+		let phi = 2f32 * PI * rng.gen::<f32>();
+
+		let u = sine * phi.cos();
+		let v = sine * phi.sin();
+		Direction::normalized(
+			u*tangent.x + v*bitangent.x + cosine*axis.x,
+			u*tangent.y + v*bitangent.y + cosine*axis.y,
+			u*tangent.z + v*bitangent.z + cosine*axis.z)
+	}
+
+	// Uniformly samples cone around axis with given half angle (in radians), which is
+	// the ring without the inner hole. Used for spot lights and glossy lobes
+	pub fn random_in_cone<R>(axis: &Direction, half_angle: f32, rng: &mut R) -> Direction
+		where R : Rng {
+
+		Direction::generate_random_in_ring(axis, 0f32, half_angle, rng)
+	}
+
 	// We use rejection method for generation. Generate in cube, and retry
 	// if we get the point outside the sphere
 	pub fn generate_random_on_hemisphere<R>(normal: &Direction, mut rng : &mut R) -> Direction
@@ -54,9 +204,56 @@ impl Direction {
 	}
 }
 
+// Rotation matrix of unit quaternion (x, y, z, w)
+pub fn quaternion_to_matrix(quaternion: [f32; 4]) -> [[f32; 3]; 3] {
+	let [x, y, z, w] = quaternion;
+	[
+		[1f32 - 2f32*(y*y + z*z), 2f32*(x*y - z*w), 2f32*(x*z + y*w)],
+		[2f32*(x*y + z*w), 1f32 - 2f32*(x*x + z*z), 2f32*(y*z - x*w)],
+		[2f32*(x*z - y*w), 2f32*(y*z + x*w), 1f32 - 2f32*(x*x + y*y)]
+	]
+}
+
+// Deterministic, nearly uniform set of count directions on the sphere (Fibonacci
+// lattice). Points are spaced uniformly in z and rotated by the golden angle
+pub fn fibonacci_sphere(count: usize) -> Vec<Direction> {
+	let golden_angle = PI * (3f32 - 5f32.sqrt());
+	(0..count).map(|i| {
+		let z = 1f32 - (2f32 * i as f32 + 1f32) / count as f32;
+		let sine = (1f32 - z * z).max(0f32).sqrt();
+		let phi = golden_angle * i as f32;
+		Direction::normalized(sine * phi.cos(), sine * phi.sin(), z)
+	}).collect()
+}
+
+// Computes chi-squared statistic of sampler against uniform distribution on
+// the sphere. Sphere is split into bins x bins equal-area cells (uniform in
+// z and in azimuth, which is equal-area by Archimedes' theorem). For a uniform
+// sampler, the result should be close to the number of cells minus one
+pub fn uniformity_chi_squared<S, R>(mut sampler: S, bins: usize, samples: u32, rng: &mut R) -> f32
+	where S: FnMut(&mut R) -> Direction, R: Rng {
+
+	let mut counts = vec![0u32; bins * bins];
+	for _i in 0..samples {
+		let d = sampler(rng);
+
+		let z_bin = (((d.z + 1f32) / 2f32 * bins as f32) as usize).min(bins - 1);
+		let phi = d.y.atan2(d.x) + PI;
+		let phi_bin = ((phi / (2f32 * PI) * bins as f32) as usize).min(bins - 1);
+		counts[z_bin * bins + phi_bin] += 1;
+	}
+
+	let expected = samples as f32 / (bins * bins) as f32;
+	counts.iter().map(|&c| {
+		let diff = c as f32 - expected;
+		diff * diff / expected
+	}).sum()
+}
+
 #[cfg(test)]
 mod tests {
 	use super::*;
+	use rand::{SeedableRng, rngs::StdRng};
 
 	#[test]
 	#[should_panic]
@@ -64,9 +261,35 @@ mod tests {
 		let _direction = Direction::new(2f32, 0f32, 1f32);
 	}
 
+	#[test]
+	fn tangent_component_is_orthogonal() {
+		let mut rng = StdRng::seed_from_u64(0);
+		let normal = Direction::normalized(0.2f32, -0.5f32, 0.7f32);
+		for _i in 0..10 {
+			let t = Direction::generate_random_on_sphere(&mut rng).tangent_component(&normal);
+			let dot = t[0] * normal.x + t[1] * normal.y + t[2] * normal.z;
+			assert!(dot.abs() < 1e-6, "Tangent component has normal part {0}", dot);
+		}
+
+		let t = normal.tangent_component(&normal);
+		assert!(t.iter().all(|c| c.abs() < 1e-6), "Expected zero, got {0:?}", t);
+	}
+
+	#[test]
+	#[should_panic]
+	fn direction_slightly_off_panics() {
+		let _direction = Direction::new(1.0001f32, 0f32, 0f32);
+	}
+
+	#[test]
+	fn direction_slightly_off_with_tolerance() {
+		let direction = Direction::new_with_tolerance(1.0001f32, 0f32, 0f32, 1e-3f32);
+		assert_eq!(direction.x, 1.0001f32);
+	}
+
 	#[test]
 	fn direction_sampling() {
-		let mut rng = rand::thread_rng();
+		let mut rng = StdRng::seed_from_u64(0);
 
 		let mut sum_x = 0f32;
 		let mut sum_y = 0f32;
@@ -87,8 +310,135 @@ mod tests {
 		sum_y /= count as f32;
 		sum_z /= count as f32;
 
-		assert!(sum_x.abs() < 0.05, "Distribution not equal in x, {0}", sum_x);
-		assert!(sum_y.abs() < 0.05, "Distribution not equal in y, {0}", sum_y);
-		assert!(sum_z.abs() < 0.05, "Distribution not equal in z, {0}", sum_z);
+		assert!(sum_x.abs() < 0.02, "Distribution not equal in x, {0}", sum_x);
+		assert!(sum_y.abs() < 0.02, "Distribution not equal in y, {0}", sum_y);
+		assert!(sum_z.abs() < 0.02, "Distribution not equal in z, {0}", sum_z);
+	}
+
+	#[test]
+	fn direction_reflect() {
+		let x = Direction::new(1f32, 0f32, 0f32);
+		let reflected = x.reflect(&x);
+		assert!( (reflected.x + 1f32).abs() < 1e-5f32, "Expected -X, got {0:?}", reflected);
+
+		let s = 1f32 / 2f32.sqrt();
+		let d = Direction::new(s, -s, 0f32);
+		let reflected = d.reflect(&Direction::new(0f32, 1f32, 0f32));
+		assert!( (reflected.x - s).abs() < 1e-5f32 && (reflected.y - s).abs() < 1e-5f32,
+			"Expected Y component to flip, got {0:?}", reflected);
+	}
+
+	#[test]
+	fn direction_refract() {
+		let normal = Direction::new(0f32, 0f32, 1f32);
+		let s = 1f32 / 2f32.sqrt();
+		let d = Direction::new(s, 0f32, -s);
+
+		// Same medium on both sides leaves direction unchanged
+		let refracted = d.refract(&normal, 1f32).unwrap();
+		assert!( (refracted.dot(&d) - 1f32).abs() < 1e-5f32, "Expected unchanged direction, got {0:?}", refracted);
+
+		// Going from dense to sparse medium at 45 degrees is past the critical angle
+		assert!(d.refract(&normal, 1.5f32).is_none(), "Expected total internal reflection");
+	}
+
+	#[test]
+	fn direction_sampling_chi_squared() {
+		let mut rng = StdRng::seed_from_u64(0);
+
+		// 64 cells give 63 degrees of freedom, threshold is roughly 5 sigma above
+		let chi2 = uniformity_chi_squared(Direction::generate_random_on_sphere, 8, 20000, &mut rng);
+		assert!(chi2 < 120f32, "Uniform sampler should pass chi-squared test, got {0}", chi2);
+
+		// Normalizing points in cube without rejection clusters them towards the corners
+		let biased = |r: &mut StdRng| Direction::normalized(
+			r.gen::<f32>() * 2f32 - 1f32, r.gen::<f32>() * 2f32 - 1f32, r.gen::<f32>() * 2f32 - 1f32);
+		let chi2 = uniformity_chi_squared(biased, 8, 20000, &mut rng);
+		assert!(chi2 > 120f32, "Biased sampler should fail chi-squared test, got {0}", chi2);
+	}
+
+	#[test]
+	fn direction_angle_and_slerp() {
+		let x = Direction::new(1f32, 0f32, 0f32);
+		let y = Direction::new(0f32, 1f32, 0f32);
+		assert!( (x.angle_to(&y) - PI / 2f32).abs() < 1e-5f32);
+
+		let half = x.slerp(&y, 0.5f32);
+		let s = 1f32 / 2f32.sqrt();
+		assert!( (half.x - s).abs() < 1e-5f32 && (half.y - s).abs() < 1e-5f32 && half.z.abs() < 1e-5f32,
+			"Expected point on great circle, got {0:?}", half);
+
+		let same = x.slerp(&x, 0.3f32);
+		assert!( (same.x - 1f32).abs() < 1e-5f32, "Expected +X, got {0:?}", same);
+	}
+
+	#[test]
+	fn direction_rotate_axis_angle() {
+		let x = Direction::new(1f32, 0f32, 0f32);
+		let z = Direction::new(0f32, 0f32, 1f32);
+
+		let rotated = x.rotate_axis_angle(z, PI / 2f32);
+		assert!(rotated.x.abs() < 1e-5f32 && (rotated.y - 1f32).abs() < 1e-5f32 && rotated.z.abs() < 1e-5f32,
+			"Expected +Y, got {0:?}", rotated);
+
+		// Rotating around itself does nothing
+		let same = z.rotate_axis_angle(z, 1f32);
+		assert!( (same.z - 1f32).abs() < 1e-5f32, "Expected +Z, got {0:?}", same);
+	}
+
+	#[test]
+	fn direction_approx_eq_and_key() {
+		let a = Direction::normalized(1f32, 0.001f32, 0f32);
+		let b = Direction::normalized(1f32, 0.002f32, 0f32);
+
+		assert!(a.approx_eq(&b, 1e-2f32));
+		assert!(!a.approx_eq(&b, 1e-4f32));
+
+		assert_eq!(a.quantized_key(10), b.quantized_key(10));
+		assert_ne!(a.quantized_key(10000), b.quantized_key(10000));
+	}
+
+	#[test]
+	fn direction_tangent_basis() {
+		for d in [Direction::new(0f32, 0f32, 1f32), Direction::new(0f32, 0f32, -1f32), Direction::normalized(1f32, 2f32, -3f32)].iter() {
+			let (t, b) = d.tangent_basis();
+			assert!(t.dot(d).abs() < 1e-5f32 && b.dot(d).abs() < 1e-5f32 && t.dot(&b).abs() < 1e-5f32,
+				"Basis of {0:?} is not orthogonal: {1:?}, {2:?}", d, t, b);
+		}
+	}
+
+	#[test]
+	fn direction_sampling_at_poles() {
+		let mut rng = StdRng::seed_from_u64(0);
+		for normal in [Direction::new(0f32, 0f32, 1f32), Direction::new(0f32, 0f32, -1f32)].iter() {
+			for _i in 0..10000 {
+				let d = Direction::generate_random_on_hemisphere(normal, &mut rng);
+				assert!(d.x.is_finite() && d.y.is_finite() && d.z.is_finite(), "Sample {0:?} is not finite", d);
+				assert!(d.dot(normal) >= 0f32, "Sample {0:?} is not on hemisphere around {1:?}", d, normal);
+
+				// Ring sampling works in the tangent frame of the normal
+				let d = Direction::generate_random_in_ring(normal, 0f32, PI / 2f32, &mut rng);
+				assert!(d.x.is_finite() && d.y.is_finite() && d.z.is_finite(), "Sample {0:?} is not finite", d);
+				assert!(d.dot(normal) >= -1e-6f32, "Sample {0:?} is not on hemisphere around {1:?}", d, normal);
+			}
+		}
 	}
-}
\ No newline at end of file
+
+	#[test]
+	fn direction_random_in_cone() {
+		let mut rng = StdRng::seed_from_u64(0);
+		let axis = Direction::new(0f32, 0f32, 1f32);
+		let half_angle = 30f32.to_radians();
+
+		let mut mean = [0f32; 2];
+		for _i in 0..10000 {
+			let d = Direction::random_in_cone(&axis, half_angle, &mut rng);
+			assert!(d.angle_to(&axis) <= half_angle + 1e-4, "Sample {0:?} is outside of the cone", d);
+			mean[0] += d.x / 10000f32;
+			mean[1] += d.y / 10000f32;
+		}
+
+		// Cone is symmetric around the axis
+		assert!(mean[0].abs() < 0.01 && mean[1].abs() < 0.01, "Mean {0:?} is not on the axis", mean);
+	}
+}
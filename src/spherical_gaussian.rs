@@ -0,0 +1,177 @@
+use rand::Rng;
+use crate::spherical::Direction;
+use crate::spherical_integration::gauss_legendre;
+
+
+// Spherical Gaussian lobe amplitude * exp(sharpness * (dot(axis, dir) - 1)). Sharp
+// lobes represent high frequency lighting (sun, small lights) much better than SH
+#[derive(Debug, Clone, Copy)]
+pub struct SphericalGaussian {
+	pub axis: Direction,
+	pub sharpness: f32,
+	pub amplitude: f32
+}
+
+impl SphericalGaussian {
+	pub fn eval(&self, direction: Direction) -> f32 {
+		self.amplitude * (self.sharpness * (self.axis.dot(&direction) - 1f32)).exp()
+	}
+
+	// Lobe is zonal around axis, so by Funk-Hecke theorem its SH coefficients are the SH
+	// functions of the axis scaled per band by 2PI * integral of G(t) P_l(t) over [-1, 1]
+	// (without amplitude). We integrate numerically, the lobe is smooth in t
+	pub fn band_scales(&self, order: usize) -> Vec<f32> {
+		let mut scales = vec![0f64; order + 1];
+		for (t, weight) in gauss_legendre(64) {
+			let value = weight * (self.sharpness as f64 * (t - 1f64)).exp();
+
+			// Legendre polynomials with the three-term recurrence
+			let mut p = 1f64;
+			let mut p_prev = 0f64;
+			for (l, scale) in scales.iter_mut().enumerate() {
+				*scale += value * p;
+				let next = ((2*l + 1) as f64 * t * p - l as f64 * p_prev) / (l + 1) as f64;
+				p_prev = p;
+				p = next;
+			}
+		}
+		scales.iter().map(|s| (2f64 * std::f64::consts::PI * s) as f32).collect()
+	}
+}
+
+// Sum of spherical Gaussian lobes
+#[derive(Debug, Clone, Default)]
+pub struct SGMixture {
+	pub lobes: Vec<SphericalGaussian>
+}
+
+impl SGMixture {
+	pub fn eval(&self, direction: Direction) -> f32 {
+		self.lobes.iter().map(|lobe| lobe.eval(direction)).sum()
+	}
+
+	// Fits lobes to function with greedy residual fitting on count random samples. Each
+	// lobe is seeded at the largest remaining residual, its sharpness picked from powers
+	// of two (1 to 256) and amplitude fitted with least squares, then subtracted. The rng
+	// is only used for the sample directions, so the same seed gives identical lobes
+	pub fn from_function<F, R>(func: F, rng: &mut R, lobes: usize, count: u32) -> SGMixture
+		where F: Fn(f32, f32, f32) -> f32, R: Rng {
+
+		SGMixture::from_function_with_residual(func, rng, lobes, count).0
+	}
+
+	// Same as from_function, also returns RMS of the residual left at the samples
+	pub fn from_function_with_residual<F, R>(func: F, mut rng: &mut R, lobes: usize, count: u32) -> (SGMixture, f32)
+		where F: Fn(f32, f32, f32) -> f32, R: Rng {
+
+		let directions: Vec<Direction> = (0..count).map(|_i| Direction::generate_random_on_sphere(&mut rng)).collect();
+		let mut residual: Vec<f32> = directions.iter().map(|d| func(d.x, d.y, d.z)).collect();
+
+		let mut mixture = SGMixture::default();
+		for _lobe in 0..lobes {
+			let seed = match residual.iter().enumerate().max_by(|a, b| a.1.total_cmp(b.1)) {
+				Some((index, _value)) => directions[index],
+				None => break
+			};
+
+			let mut lobe = SGMixture::fit_lobe(&directions, &residual, seed);
+
+			// Move axis to the residual weighted center of the lobe and refit
+			let (mut x, mut y, mut z) = (0f32, 0f32, 0f32);
+			for (d, r) in directions.iter().zip(residual.iter()) {
+				let weight = r.max(0f32) * lobe.eval(*d);
+				x += weight * d.x;
+				y += weight * d.y;
+				z += weight * d.z;
+			}
+			if x*x + y*y + z*z > 0f32 {
+				lobe = SGMixture::fit_lobe(&directions, &residual, Direction::normalized(x, y, z));
+			}
+
+			if lobe.amplitude <= 0f32 {
+				break;
+			}
+			for (d, r) in directions.iter().zip(residual.iter_mut()) {
+				*r -= lobe.eval(*d);
+			}
+			mixture.lobes.push(lobe);
+		}
+
+		let squared: f32 = residual.iter().map(|r| r * r).sum();
+		let rms = if count > 0 { (squared / count as f32).sqrt() } else { 0f32 };
+		(mixture, rms)
+	}
+
+	// Lobe around axis with the sharpness and amplitude that reduce the residual the most
+	fn fit_lobe(directions: &[Direction], residual: &[f32], axis: Direction) -> SphericalGaussian {
+		let mut best = SphericalGaussian { axis, sharpness: 1f32, amplitude: 0f32 };
+		let mut best_reduction = 0f32;
+		for power in 0..9 {
+			let sharpness = (1 << power) as f32;
+			let unit = SphericalGaussian { axis, sharpness, amplitude: 1f32 };
+
+			let mut correlation = 0f32;
+			let mut norm = 0f32;
+			for (d, r) in directions.iter().zip(residual.iter()) {
+				let g = unit.eval(*d);
+				correlation += r * g;
+				norm += g * g;
+			}
+
+			// Least squares amplitude is correlation/norm, which reduces the squared
+			// residual by correlation^2/norm
+			if norm > 0f32 && correlation * correlation / norm > best_reduction {
+				best_reduction = correlation * correlation / norm;
+				best = SphericalGaussian { axis, sharpness, amplitude: correlation / norm };
+			}
+		}
+		best
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use rand::{SeedableRng, rngs::StdRng};
+	use std::f32::consts::PI;
+
+	#[test]
+	fn fit_single_lobe() {
+		let mut rng = StdRng::seed_from_u64(0);
+		let lobe = SphericalGaussian { axis: Direction::normalized(1f32, -0.5f32, 0.3f32), sharpness: 16f32, amplitude: 3f32 };
+
+		let mixture = SGMixture::from_function(|x, y, z| lobe.eval(Direction::new(x, y, z)), &mut rng, 1, 4000);
+		assert_eq!(mixture.lobes.len(), 1);
+		let angle = mixture.lobes[0].axis.angle_to(&lobe.axis);
+		assert!(angle < 0.05, "Recovered axis {0:?} is {1} radians from {2:?}", mixture.lobes[0].axis, angle, lobe.axis);
+		assert_eq!(mixture.lobes[0].sharpness, 16f32);
+	}
+
+	#[test]
+	fn fit_is_deterministic() {
+		let func = |x: f32, y: f32, z: f32| 2f32 * (8f32 * (z - 1f32)).exp() + (2f32 * (0.6f32 * x + 0.8f32 * y - 1f32)).exp();
+
+		let (first, first_residual) = SGMixture::from_function_with_residual(func, &mut StdRng::seed_from_u64(5), 3, 2000);
+		let (second, second_residual) = SGMixture::from_function_with_residual(func, &mut StdRng::seed_from_u64(5), 3, 2000);
+		assert_eq!(first.lobes.len(), second.lobes.len());
+		for (a, b) in first.lobes.iter().zip(second.lobes.iter()) {
+			assert_eq!([a.axis.x, a.axis.y, a.axis.z].map(f32::to_bits), [b.axis.x, b.axis.y, b.axis.z].map(f32::to_bits));
+			assert_eq!(a.sharpness.to_bits(), b.sharpness.to_bits());
+			assert_eq!(a.amplitude.to_bits(), b.amplitude.to_bits());
+		}
+		assert_eq!(first_residual.to_bits(), second_residual.to_bits());
+
+		// More lobes leave less residual
+		let (_mixture, single_residual) = SGMixture::from_function_with_residual(func, &mut StdRng::seed_from_u64(5), 1, 2000);
+		assert!(first_residual < single_residual, "Residual of 3 lobes {0} is not below 1 lobe {1}", first_residual, single_residual);
+	}
+
+	#[test]
+	fn band_scales_constant() {
+		// Band 0 is the integral of the lobe over the sphere, 2PI/s (1 - exp(-2s))
+		let lobe = SphericalGaussian { axis: Direction::new(0f32, 0f32, 1f32), sharpness: 8f32, amplitude: 1f32 };
+		let expected = 2f32 * PI / 8f32 * (1f32 - (-16f32).exp());
+		let result = lobe.band_scales(2)[0];
+		assert!( (result - expected).abs() < 1e-5, "Result is {0}, expected {1}", result, expected);
+	}
+}
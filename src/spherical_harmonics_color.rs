@@ -0,0 +1,509 @@
+use rand::Rng;
+use std::convert::TryFrom;
+use crate::spherical::{Direction, quaternion_to_matrix};
+use crate::spherical_harmonics::SHFuncApproximation;
+use crate::error::{Error, Result};
+#[cfg(feature = "image-io")]
+use crate::envmap::texel_direction;
+#[cfg(feature = "image-io")]
+use crate::image_io::{write_exr_header, write_exr_scanline};
+
+
+// Spherical harmonic approximation of a colored function on sphere,
+// one SH approximation per red, green and blue channel
+#[derive(Debug, Clone)]
+pub struct SHColorApproximation {
+	channels: [SHFuncApproximation; 3]
+}
+
+impl Default for SHColorApproximation {
+	fn default() -> SHColorApproximation {
+		SHColorApproximation::new()
+	}
+}
+
+impl SHColorApproximation {
+	pub fn new() -> SHColorApproximation {
+		SHColorApproximation::with_order(2)
+	}
+
+	pub fn with_order(order: usize) -> SHColorApproximation {
+		SHColorApproximation { channels: [
+			SHFuncApproximation::with_order(order),
+			SHFuncApproximation::with_order(order),
+			SHFuncApproximation::with_order(order)
+		]}
+	}
+
+	// Combines separately baked red, green and blue approximations
+	pub fn from_channels(red: SHFuncApproximation, green: SHFuncApproximation, blue: SHFuncApproximation) -> SHColorApproximation {
+		assert!(red.order() == green.order() && red.order() == blue.order(), "Channels must be of the same order");
+		SHColorApproximation { channels: [red, green, blue] }
+	}
+
+	// Reads coefficients stored as consecutive RGB triples, one per SH function, as GPU
+	// buffers usually store them (27 floats for 9 coefficients)
+	pub fn from_interleaved_rgb(data: &[f32]) -> Result<SHColorApproximation> {
+		if !data.len().is_multiple_of(3) {
+			return Err(Error::InvalidLength { expected: data.len() / 3 * 3, actual: data.len() });
+		}
+		let count = data.len() / 3;
+		let order = (count as f32).sqrt() as usize;
+		if order == 0 || order * order != count {
+			return Err(Error::InvalidCoefficientCount(count));
+		}
+
+		let channel = |c: usize| SHFuncApproximation::from_coefficients(data.chunks(3).map(|rgb| rgb[c]).collect());
+		Ok(SHColorApproximation { channels: [channel(0), channel(1), channel(2)] })
+	}
+
+	// Inverse of from_interleaved_rgb
+	pub fn to_interleaved_rgb(&self) -> Vec<f32> {
+		let mut data = Vec::with_capacity(3 * self.channels[0].coefficients().len());
+		for i in 0..self.channels[0].coefficients().len() {
+			for channel in self.channels.iter() {
+				data.push(channel.coefficients()[i]);
+			}
+		}
+		data
+	}
+
+	pub fn order(&self) -> usize {
+		self.channels[0].order()
+	}
+
+	pub fn channels(&self) -> &[SHFuncApproximation; 3] {
+		&self.channels
+	}
+
+	// Multiplies all channels with scalar in place
+	pub fn mul_in_place(&mut self, scalar: f32) {
+		for channel in self.channels.iter_mut() {
+			channel.mul_in_place(scalar);
+		}
+	}
+
+	// Adds other channels to self
+	pub fn add_in_place(&mut self, other: &SHColorApproximation) {
+		for (channel, other) in self.channels.iter_mut().zip(other.channels.iter()) {
+			channel.add_in_place(other);
+		}
+	}
+
+	// Adds other channels multiplied by per channel scale to self, for summing
+	// colored lights without temporaries
+	pub fn add_scaled(&mut self, other: &SHColorApproximation, scale: [f32; 3]) {
+		for ((channel, other), scale) in self.channels.iter_mut().zip(other.channels.iter()).zip(scale.iter()) {
+			channel.add_in_place_scaled(other, *scale);
+		}
+	}
+
+	// Adds SH functions in basis (as computed with from_direction) scaled
+	// per channel, the building block of all projections
+	pub fn add_scaled_basis(&mut self, basis: &SHFuncApproximation, scale: [f32; 3]) {
+		for (channel, scale) in self.channels.iter_mut().zip(scale.iter()) {
+			channel.add_in_place_scaled(basis, *scale);
+		}
+	}
+
+	// Luminance of the colored function (Rec. 709 weights). Luminance is linear
+	// in the channels, so we can combine the coefficients directly
+	pub fn luminance(&self) -> SHFuncApproximation {
+		let mut result = SHFuncApproximation::with_order(self.order());
+		for (channel, weight) in self.channels.iter().zip([0.2126f32, 0.7152f32, 0.0722f32].iter()) {
+			result.add_in_place_scaled(channel, *weight);
+		}
+		result
+	}
+
+	// Direction towards which the color shifts to warm, the dominant direction of red minus
+	// blue. Shows where colored light leaks from, independent of overall brightness. If
+	// red and blue have the same L1 band there is no color shift, and we return +z
+	pub fn chromatic_direction(&self) -> Direction {
+		let mut warm = self.channels[0].clone();
+		warm.add_in_place_scaled(&self.channels[2], -1f32);
+		warm.dominant_direction().unwrap_or_else(|| Direction::new(0f32, 0f32, 1f32))
+	}
+
+	// Largest reconstructed luminance over the sphere, for setting exposure without
+	// reconstructing the whole environment
+	pub fn peak_luminance<R: Rng>(&self, rng: &mut R, samples: u32) -> f32 {
+		self.luminance().argmax(rng, samples).1
+	}
+
+	// Evaluates the color in certain direction, workspace must match the order
+	pub fn eval(&self, direction: Direction, workspace: &mut SHFuncApproximation) -> [f32; 3] {
+		workspace.from_direction(direction);
+		let mut color = [0f32; 3];
+		for (value, channel) in color.iter_mut().zip(self.channels.iter()) {
+			*value = channel.convolution(workspace) / (4f32 * std::f32::consts::PI);
+		}
+		color
+	}
+
+	// Writes tone mapped equirectangular view of the approximation to an 8-bit PNG, for
+	// quick visual inspection. Colors are multiplied by exposure, Reinhard tone mapped
+	// x / (1 + x) and gamma corrected. Negative ringing is shown as black
+	#[cfg(feature = "image-io")]
+	pub fn save_preview_png<P: AsRef<std::path::Path>>(&self, path: P, width: usize, height: usize, exposure: f32) -> image::ImageResult<()> {
+		let mut workspace = SHFuncApproximation::with_order(self.order());
+		let mut pixels = Vec::with_capacity(width * height * 3);
+		for row in 0..height {
+			for column in 0..width {
+				let direction = texel_direction(column as f32 + 0.5f32, row as f32 + 0.5f32, width, height);
+				for value in self.eval(direction, &mut workspace).iter() {
+					let exposed = (value * exposure).max(0f32);
+					let mapped = (exposed / (1f32 + exposed)).powf(1f32 / 2.2f32);
+					pixels.push((mapped * 255f32).round() as u8);
+				}
+			}
+		}
+		let image = image::RgbImage::from_raw(width as u32, height as u32, pixels).expect("Pixel buffer matches the image size");
+		image.save(path)
+	}
+
+	// Writes equirectangular reconstruction as float OpenEXR (linear, no tone mapping).
+	// Rows are evaluated and written one by one, so only a single row is held in memory
+	#[cfg(feature = "image-io")]
+	pub fn write_equirect_exr<W: std::io::Write>(&self, mut writer: W, width: usize, height: usize) -> std::io::Result<()> {
+		write_exr_header(&mut writer, width, height)?;
+
+		let mut workspace = SHFuncApproximation::with_order(self.order());
+		let mut texels = Vec::with_capacity(width);
+		for row in 0..height {
+			texels.clear();
+			for column in 0..width {
+				let direction = texel_direction(column as f32 + 0.5f32, row as f32 + 0.5f32, width, height);
+				texels.push(self.eval(direction, &mut workspace));
+			}
+			write_exr_scanline(&mut writer, row, &texels)?;
+		}
+		Ok(())
+	}
+
+	// Rotates all channels, see SHFuncApproximation::rotate. Coefficient transform is
+	// computed once and shared by the channels
+	pub fn rotate(&self, rotation: &[[f32; 3]; 3]) -> SHColorApproximation {
+		let transform = SHFuncApproximation::rotation_transform(rotation, self.order());
+		SHColorApproximation { channels: [
+			self.channels[0].apply_transform(&transform),
+			self.channels[1].apply_transform(&transform),
+			self.channels[2].apply_transform(&transform)
+		]}
+	}
+
+	// Same as rotate, with rotation given as unit quaternion (x, y, z, w)
+	pub fn rotate_quat(&self, quaternion: [f32; 4]) -> SHColorApproximation {
+		self.rotate(&quaternion_to_matrix(quaternion))
+	}
+
+	// Tangent gradient of each channel (see SHFuncApproximation::eval_gradient). Gradients
+	// of the SH functions are computed once and shared by all channels
+	pub fn eval_gradient(&self, direction: Direction) -> [[f32; 3]; 3] {
+		let gradients = SHFuncApproximation::basis_gradient(direction, self.order());
+
+		let mut result = [[0f32; 3]; 3];
+		for (channel_gradient, channel) in result.iter_mut().zip(self.channels.iter()) {
+			for (c, gradient) in channel.coefficients().iter().zip(gradients.iter()) {
+				for k in 0..3 {
+					channel_gradient[k] += 4f32 * std::f32::consts::PI * c * gradient[k];
+				}
+			}
+		}
+		result
+	}
+
+	// Mean CIE76 color difference between reconstructions of self and reference over
+	// random directions. Perceptual quality metric for compression, e.g. lower order.
+	// Colors are linear sRGB, relative to white of 1, and negative ringing counts as black
+	pub fn delta_e_vs<R: Rng>(&self, reference: &SHColorApproximation, mut rng: &mut R, samples: u32) -> f32 {
+		let mut workspace = SHFuncApproximation::with_order(self.order());
+		let mut reference_workspace = SHFuncApproximation::with_order(reference.order());
+
+		let mut total = 0f32;
+		for _i in 0..samples {
+			let direction = Direction::generate_random_on_sphere(&mut rng);
+			let a = linear_rgb_to_lab(self.eval(direction, &mut workspace));
+			let b = linear_rgb_to_lab(reference.eval(direction, &mut reference_workspace));
+			total += ((a[0] - b[0]).powi(2) + (a[1] - b[1]).powi(2) + (a[2] - b[2]).powi(2)).sqrt();
+		}
+		if samples > 0 { total / samples as f32 } else { 0f32 }
+	}
+
+	// Approximate colored function with SH using Monte Carlo sampling. Each direction
+	// is sampled only once for all three channels
+	pub fn from_function<F, R>(func: F, mut rng: &mut R, count: u32) -> SHColorApproximation
+		where F : Fn(f32, f32, f32) -> [f32; 3], R : Rng {
+
+		let mut approximation = SHColorApproximation::new();
+		let mut basis = SHFuncApproximation::new();
+
+		for _i in 0..count {
+			let direction = Direction::generate_random_on_sphere(&mut rng);
+			basis.from_direction(direction);
+
+			approximation.add_scaled_basis(&basis, func(direction.x, direction.y, direction.z));
+		}
+
+		// Normalize by the amount of samples
+		if count > 0 {
+			approximation.mul_in_place(1f32 / (count as f32));
+		}
+		approximation
+	}
+}
+
+// CIE Lab of linear sRGB color (D65 white point)
+fn linear_rgb_to_lab(rgb: [f32; 3]) -> [f32; 3] {
+	let [r, g, b] = [rgb[0].max(0f32), rgb[1].max(0f32), rgb[2].max(0f32)];
+	let x = (0.4124f32 * r + 0.3576f32 * g + 0.1805f32 * b) / 0.9505f32;
+	let y = 0.2126f32 * r + 0.7152f32 * g + 0.0722f32 * b;
+	let z = (0.0193f32 * r + 0.1192f32 * g + 0.9505f32 * b) / 1.089f32;
+
+	let f = |t: f32| if t > 0.008856f32 { t.cbrt() } else { 7.787f32 * t + 16f32 / 116f32 };
+	let (fx, fy, fz) = (f(x), f(y), f(z));
+	[116f32 * fy - 16f32, 500f32 * (fx - fy), 200f32 * (fy - fz)]
+}
+
+impl TryFrom<&[f32]> for SHColorApproximation {
+	type Error = Error;
+
+	fn try_from(data: &[f32]) -> Result<SHColorApproximation> {
+		SHColorApproximation::from_interleaved_rgb(data)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use rand::{SeedableRng, rngs::StdRng};
+
+	#[test]
+	fn constant_color() {
+		let mut rng = StdRng::seed_from_u64(0);
+		let sh = SHColorApproximation::from_function(|_x,_y,_z| [1f32, 0.5f32, 0.25f32], &mut rng, 10000);
+
+		let mut workspace = SHFuncApproximation::new();
+		let color = sh.eval(Direction::new(0f32, 0f32, 1f32), &mut workspace);
+		let expected = [1f32, 0.5f32, 0.25f32];
+		for (result, expected) in color.iter().zip(expected.iter()) {
+			assert!( (result - expected).abs() < 0.1, "Result is {0}, expected {1}", result, expected);
+		}
+	}
+
+	#[test]
+	fn from_channels_constant() {
+		// Constant has only the first coefficient, reconstruction is 4PI * Y00 * c0
+		let channel = |value: f32| {
+			let c0 = value / (4f32 * std::f32::consts::PI * 0.28209479f32);
+			SHFuncApproximation::from([c0, 0f32, 0f32, 0f32, 0f32, 0f32, 0f32, 0f32, 0f32])
+		};
+		let sh = SHColorApproximation::from_channels(channel(1f32), channel(0.5f32), channel(0.25f32));
+
+		let mut workspace = SHFuncApproximation::new();
+		let color = sh.eval(Direction::new(0f32, 1f32, 0f32), &mut workspace);
+		let expected = [1f32, 0.5f32, 0.25f32];
+		for (result, expected) in color.iter().zip(expected.iter()) {
+			assert!( (result - expected).abs() < 1e-5, "Result is {0}, expected {1}", result, expected);
+		}
+	}
+
+	#[test]
+	#[should_panic]
+	fn from_channels_mismatched_order() {
+		SHColorApproximation::from_channels(SHFuncApproximation::new(), SHFuncApproximation::with_order(3), SHFuncApproximation::new());
+	}
+
+	#[test]
+	fn add_scaled_lights() {
+		let mut rng = StdRng::seed_from_u64(0);
+		let a = SHColorApproximation::from_function(|x,_y,_z| [x.max(0f32), 1f32, 0f32], &mut rng, 100);
+		let b = SHColorApproximation::from_function(|_x,y,z| [0.5f32, y * z, z.max(0f32)], &mut rng, 100);
+
+		let mut sum = SHColorApproximation::new();
+		sum.add_scaled(&a, [1f32, 0.5f32, 2f32]);
+		sum.add_scaled(&b, [0.25f32, 3f32, -1f32]);
+
+		for (channel, (a_scale, b_scale)) in [(1f32, 0.25f32), (0.5f32, 3f32), (2f32, -1f32)].iter().enumerate() {
+			let mut expected = SHFuncApproximation::new();
+			expected.add_in_place_scaled(&a.channels[channel], *a_scale);
+			expected.add_in_place_scaled(&b.channels[channel], *b_scale);
+			assert_eq!(sum.channels[channel].coefficients(), expected.coefficients());
+		}
+	}
+
+	#[test]
+	fn eval_gradient_matches_differences() {
+		let mut rng = StdRng::seed_from_u64(0);
+		let sh = SHColorApproximation::from_function(|x,y,z| [x*y, 0.5f32*z, 1f32 - x], &mut rng, 1000);
+
+		let mut workspace = SHFuncApproximation::new();
+		let direction = Direction::new(0f32, 0.6f32, 0.8f32);
+		let gradient = sh.eval_gradient(direction);
+
+		// Derivatives along tangents (1, 0, 0) and (0, 0.8, -0.6) of the sphere at direction
+		let h = 1e-2f32;
+		for (k, (plus, minus)) in [
+			(Direction::normalized(h, 0.6f32, 0.8f32), Direction::normalized(-h, 0.6f32, 0.8f32)),
+			(Direction::normalized(0f32, 0.6f32 + 0.8f32*h, 0.8f32 - 0.6f32*h), Direction::normalized(0f32, 0.6f32 - 0.8f32*h, 0.8f32 + 0.6f32*h))
+		].iter().enumerate() {
+			let forward = sh.eval(*plus, &mut workspace);
+			let backward = sh.eval(*minus, &mut workspace);
+			for channel in 0..3 {
+				let expected = (forward[channel] - backward[channel]) / (2f32 * h);
+				let g = gradient[channel];
+				let result = if k == 0 { g[0] } else { 0.8f32 * g[1] - 0.6f32 * g[2] };
+				assert!( (result - expected).abs() < 1e-2, "Result is {0}, expected {1}", result, expected);
+			}
+		}
+	}
+
+	#[test]
+	fn chromatic_direction_points_to_red() {
+		let mut rng = StdRng::seed_from_u64(0);
+
+		// Bright white from above, red on +x side and blue on -x side
+		let sh = SHColorApproximation::from_function(|x, _y, z| {
+			let white = 5f32 * z.max(0f32);
+			[white + x.max(0f32), white, white + (-x).max(0f32)]
+		}, &mut rng, 10000);
+
+		let direction = sh.chromatic_direction();
+		assert!(direction.x > 0.9, "Expected direction close to +x, got {0:?}", direction);
+
+		// Grey probe has no color shift and falls back to +z
+		let grey = SHColorApproximation::from_function(|_x, _y, z| [z, z, z], &mut rng, 100).chromatic_direction();
+		assert_eq!((grey.x, grey.y, grey.z), (0f32, 0f32, 1f32));
+	}
+
+	#[test]
+	fn delta_e_grows_with_truncation() {
+		let mut rng = StdRng::seed_from_u64(0);
+		let channel = |scale: f32, rng: &mut StdRng| SHFuncApproximation::from_function_with_order(4,
+			|x, _y, z| scale * (0.2f32 + z.max(0f32).powi(4) + 0.5f32 * x.max(0f32)), rng, 10000);
+		let red = channel(1f32, &mut rng);
+		let green = channel(0.7f32, &mut rng);
+		let blue = channel(0.4f32, &mut rng);
+		let sh = SHColorApproximation::from_channels(red, green, blue);
+
+		let identical = sh.delta_e_vs(&sh.clone(), &mut rng, 500);
+		assert!(identical < 1e-3, "Identical probes differ by {0}", identical);
+
+		let mut previous = 0f32;
+		for order in (0..4).rev() {
+			let channels = sh.channels();
+			let truncated = SHColorApproximation::from_channels(channels[0].truncate_to_order(order),
+				channels[1].truncate_to_order(order), channels[2].truncate_to_order(order));
+			let delta_e = truncated.delta_e_vs(&sh, &mut StdRng::seed_from_u64(1), 500);
+			assert!(delta_e > previous, "Order {0} has delta E {1}, higher order had {2}", order, delta_e, previous);
+			previous = delta_e;
+		}
+	}
+
+	#[test]
+	fn lab_of_white_and_black() {
+		let white = linear_rgb_to_lab([1f32, 1f32, 1f32]);
+		assert!( (white[0] - 100f32).abs() < 0.1 && white[1].abs() < 0.1 && white[2].abs() < 0.1, "White is {0:?}", white);
+		assert!(linear_rgb_to_lab([0f32, 0f32, 0f32])[0].abs() < 1e-4);
+	}
+
+	#[test]
+	fn interleaved_rgb_round_trip() {
+		use std::convert::TryInto;
+
+		let data: Vec<f32> = (0..27).map(|i| i as f32 * 0.5f32).collect();
+		let sh = SHColorApproximation::from_interleaved_rgb(&data).unwrap();
+		assert_eq!(sh.order(), 2);
+		assert_eq!(sh.channels[1].coefficients()[2], data[7]);
+		assert_eq!(sh.to_interleaved_rgb(), data);
+
+		let converted: SHColorApproximation = data[..12].try_into().unwrap();
+		assert_eq!(converted.order(), 1);
+		assert_eq!(converted.to_interleaved_rgb(), &data[..12]);
+	}
+
+	#[test]
+	fn interleaved_rgb_wrong_length() {
+		let data = [0f32; 27];
+		assert_eq!(SHColorApproximation::from_interleaved_rgb(&data[..26]).unwrap_err(), Error::InvalidLength { expected: 24, actual: 26 });
+		assert_eq!(SHColorApproximation::from_interleaved_rgb(&data[..24]).unwrap_err(), Error::InvalidCoefficientCount(8));
+		assert_eq!(SHColorApproximation::from_interleaved_rgb(&[]).unwrap_err(), Error::InvalidCoefficientCount(0));
+	}
+
+	#[test]
+	fn rotate_matches_channels() {
+		use crate::spherical_harmonics::assert_sh_close;
+
+		let mut rng = StdRng::seed_from_u64(0);
+		let sh = SHColorApproximation::from_function(|x,y,z| [x.max(0f32), 1f32 + y, x*z], &mut rng, 1000);
+
+		// 90 degrees around z
+		let rotation = [[0f32, -1f32, 0f32], [1f32, 0f32, 0f32], [0f32, 0f32, 1f32]];
+		let rotated = sh.rotate(&rotation);
+		let half = std::f32::consts::FRAC_1_SQRT_2;
+		let rotated_quat = sh.rotate_quat([0f32, 0f32, half, half]);
+		for channel in 0..3 {
+			let expected = sh.channels[channel].rotate(&rotation);
+			assert_sh_close(&rotated.channels[channel], &expected, 1e-6);
+			assert_sh_close(&rotated_quat.channels[channel], &expected, 1e-5);
+		}
+
+		// Red lobe at +x moves to +y
+		let mut workspace = SHFuncApproximation::new();
+		let red = rotated.eval(Direction::new(0f32, 1f32, 0f32), &mut workspace)[0];
+		let expected = sh.eval(Direction::new(1f32, 0f32, 0f32), &mut workspace)[0];
+		assert!( (red - expected).abs() < 1e-4, "Result is {0}, expected {1}", red, expected);
+	}
+
+	#[test]
+	fn peak_luminance_of_directional_source() {
+		let mut rng = StdRng::seed_from_u64(0);
+		let axis = Direction::normalized(1f32, 1f32, 0f32);
+
+		// Smooth lobe that is exactly band limited, with peak value of 8 at axis
+		let sh = SHColorApproximation::from_function(|x,y,z| {
+			let value = 8f32 * (1f32 + axis.dot(&Direction::new(x,y,z))) / 2f32;
+			[value, value, value]
+		}, &mut rng, 10000);
+
+		let peak = sh.peak_luminance(&mut rng, 100);
+		assert!( (peak - 8f32).abs() < 0.4, "Result is {0}, expected 8", peak);
+	}
+
+	#[cfg(feature = "image-io")]
+	#[test]
+	fn preview_png_round_trip() {
+		let mut rng = StdRng::seed_from_u64(0);
+		let sh = SHColorApproximation::from_function(|_x,_y,z| [1f32 + z, 0.5f32, 0.25f32], &mut rng, 1000);
+
+		let path = std::env::temp_dir().join(format!("sh_preview_{0}.png", std::process::id()));
+		sh.save_preview_png(&path, 64, 32, 1f32).unwrap();
+		let size = std::fs::metadata(&path).unwrap().len();
+		let image = image::open(&path).unwrap();
+		std::fs::remove_file(&path).unwrap();
+
+		assert!(size > 0);
+		assert_eq!((image.width(), image.height()), (64, 32));
+	}
+
+	#[cfg(feature = "image-io")]
+	#[test]
+	fn equirect_exr_header() {
+		let mut rng = StdRng::seed_from_u64(0);
+		let sh = SHColorApproximation::from_function(|_x,_y,z| [1f32 + z, 0.5f32, 0.25f32], &mut rng, 1000);
+
+		let mut bytes = Vec::new();
+		sh.write_equirect_exr(&mut bytes, 16, 8).unwrap();
+		assert_eq!(&bytes[0..4], &[0x76, 0x2F, 0x31, 0x01]);
+
+		let key = b"dataWindow\0box2i\0";
+		let start = bytes.windows(key.len()).position(|w| w == key).unwrap() + key.len() + 4;
+		let window: Vec<i32> = bytes[start..start + 16].chunks(4)
+			.map(|c| i32::from_le_bytes([c[0], c[1], c[2], c[3]])).collect();
+		assert_eq!(window, vec![0, 0, 15, 7]);
+
+		// Last scanline is row 7 with 16 texels of 3 floats
+		let last = bytes.len() - (8 + 16 * 3 * 4);
+		assert_eq!(&bytes[last..last + 4], &7i32.to_le_bytes());
+	}
+}
@@ -1,10 +1,19 @@
 use crate::spherical::Direction;
 use std::f32::consts::PI;
-use rand::Rng;
+use rand::{Rng, RngCore};
+use crate::sampler::SphereSampler;
 
+// Monte Carlo integration over the whole sphere. Directions are sampled
+// uniformly, so the pdf is 1/4PI
 pub fn integrate_real_space<F, R>(func: F, mut rand: &mut R, count: u32) -> f32 
 	where F: Fn(f32, f32, f32) -> f32, R: Rng {
 
+	if count == 0 {
+		return 0f32;
+	}
+
+	let pdf = 1f32 / (4f32 * PI);
+
 	let mut sum = 0f32;
 	for _i in 0..count {
 		let direction = Direction::generate_random_on_sphere(&mut rand);
@@ -12,12 +21,21 @@ pub fn integrate_real_space<F, R>(func: F, mut rand: &mut R, count: u32) -> f32
 		sum += func(direction.x, direction.y, direction.z);
 	}
 
-	4f32 * PI * sum / (count as f32)
+	// Pdf is constant, so we divide once to avoid accumulating rounding errors
+	sum / (pdf * count as f32)
 }
 
+// Monte Carlo integration over the hemisphere around normal. Directions are
+// sampled uniformly on the hemisphere, so the pdf is 1/2PI
 pub fn integrate_real_space_hemisphere<F, R>(normal: &Direction, func: F, mut rand: &mut R, count: u32) -> f32 
 	where F: Fn(f32, f32, f32) -> f32, R: Rng {
 
+	if count == 0 {
+		return 0f32;
+	}
+
+	let pdf = 1f32 / (2f32 * PI);
+
 	let mut sum = 0f32;
 	for _i in 0..count {
 		let direction = Direction::generate_random_on_hemisphere(normal, &mut rand);
@@ -25,5 +43,257 @@ pub fn integrate_real_space_hemisphere<F, R>(normal: &Direction, func: F, mut ra
 		sum += func(direction.x, direction.y, direction.z);
 	}
 
-	4f32 * PI * sum / (count as f32)
+	// Pdf is constant, so we divide once to avoid accumulating rounding errors
+	sum / (pdf * count as f32)
+}
+
+// Same as integrate_real_space_hemisphere, but keeps sampling (in batches) until the
+// standard error of the estimate drops below target_error or max_samples are taken.
+// Returns the estimate, its standard error and the number of samples used
+pub fn integrate_hemisphere_adaptive<F, R>(normal: &Direction, func: F, mut rand: &mut R, target_error: f32, max_samples: u32) -> (f32, f32, u32)
+	where F: Fn(f32, f32, f32) -> f32, R: Rng {
+
+	let batch = 64;
+	let pdf = 1f64 / (2f64 * std::f64::consts::PI);
+
+	// Running mean and variance (Welford), in double to keep them stable for many samples
+	let mut count = 0u32;
+	let mut mean = 0f64;
+	let mut squares = 0f64;
+	let mut error = f64::INFINITY;
+	while count < max_samples {
+		for _i in 0..batch.min(max_samples - count) {
+			let direction = Direction::generate_random_on_hemisphere(normal, &mut rand);
+			let value = func(direction.x, direction.y, direction.z) as f64 / pdf;
+
+			count += 1;
+			let delta = value - mean;
+			mean += delta / count as f64;
+			squares += delta * (value - mean);
+		}
+
+		if count > 1 {
+			error = (squares / ((count - 1) as f64 * count as f64)).sqrt();
+			if error < target_error as f64 {
+				break;
+			}
+		}
+	}
+	(mean as f32, error as f32, count)
+}
+
+// Monte Carlo integration over spherical ring around axis, between polar angles inner_angle
+// and outer_angle (in radians). Directions are uniform in the ring, so the pdf is one over
+// its solid angle 2PI (cos(inner) - cos(outer))
+pub fn integrate_spherical_ring<F, R>(axis: &Direction, inner_angle: f32, outer_angle: f32, func: F, rand: &mut R, count: u32) -> f32
+	where F: Fn(f32, f32, f32) -> f32, R: Rng {
+
+	assert!(inner_angle <= outer_angle, "Inner angle must not be larger than outer angle");
+	if count == 0 {
+		return 0f32;
+	}
+
+	let pdf = 1f32 / (2f32 * PI * (inner_angle.cos() - outer_angle.cos()));
+
+	let mut sum = 0f32;
+	for _i in 0..count {
+		let direction = Direction::generate_random_in_ring(axis, inner_angle, outer_angle, rand);
+
+		sum += func(direction.x, direction.y, direction.z);
+	}
+
+	// Pdf is constant, so we divide once to avoid accumulating rounding errors
+	sum / (pdf * count as f32)
+}
+
+// Monte Carlo integration over the sphere combining two samplers with multiple importance
+// sampling. Each iteration takes one direction from each sampler. With the balance heuristic
+// weight p_a / (p_a + p_b), every sample contributes func / (p_a + p_b), so the estimate is
+// good wherever at least one of the samplers matches the integrand
+pub fn integrate_mis<F>(sampler_a: &mut dyn SphereSampler, sampler_b: &mut dyn SphereSampler, func: F, rng: &mut dyn RngCore, count: u32) -> f32
+	where F: Fn(f32, f32, f32) -> f32 {
+
+	if count == 0 {
+		return 0f32;
+	}
+
+	let mut sum = 0f32;
+	for _i in 0..count {
+		for sampler in 0..2 {
+			let (direction, _pdf) = if sampler == 0 { sampler_a.next_direction(rng) } else { sampler_b.next_direction(rng) };
+
+			let pdf = sampler_a.pdf(&direction) + sampler_b.pdf(&direction);
+			if pdf > 0f32 {
+				sum += func(direction.x, direction.y, direction.z) / pdf;
+			}
+		}
+	}
+	sum / count as f32
+}
+
+// Gauss-Legendre nodes and weights on [-1, 1], exact for polynomials up to degree 2n-1.
+// We find roots of Legendre polynomial with Newton iterations
+pub fn gauss_legendre(n: usize) -> Vec<(f64, f64)> {
+	let mut nodes = Vec::with_capacity(n);
+	for i in 0..n {
+		let mut x = (std::f64::consts::PI * (i as f64 + 0.75) / (n as f64 + 0.5)).cos();
+		let mut derivative = 1f64;
+
+		for _iteration in 0..100 {
+			// Evaluate P_n(x) and derivative with the three-term recurrence
+			let mut p = 1f64;
+			let mut p_prev = 0f64;
+			for k in 1..=n {
+				let next = ((2*k - 1) as f64 * x * p - (k - 1) as f64 * p_prev) / k as f64;
+				p_prev = p;
+				p = next;
+			}
+			derivative = n as f64 * (x * p - p_prev) / (x * x - 1f64);
+
+			let step = p / derivative;
+			x -= step;
+			if step.abs() < 1e-15 {
+				break;
+			}
+		}
+
+		nodes.push((x, 2f64 / ((1f64 - x * x) * derivative * derivative)));
+	}
+	nodes
+}
+
+// Directions and weights that integrate any polynomial in x, y, z up to given degree
+// exactly over the sphere. Since SH functions are polynomials, this allows exact
+// (noise free) integration of products of SH approximations
+pub fn sphere_quadrature(degree: usize) -> Vec<(Direction, f32)> {
+	let azimuth_count = degree + 1;
+	let azimuth_weight = 2f64 * std::f64::consts::PI / azimuth_count as f64;
+
+	let mut nodes = Vec::new();
+	for (z, weight) in gauss_legendre(degree / 2 + 1) {
+		let r = (1f64 - z * z).sqrt();
+		for i in 0..azimuth_count {
+			let phi = azimuth_weight * i as f64;
+			let direction = Direction::normalized((r * phi.cos()) as f32, (r * phi.sin()) as f32, z as f32);
+			nodes.push((direction, (weight * azimuth_weight) as f32));
+		}
+	}
+	nodes
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use rand::{SeedableRng, rngs::StdRng};
+
+	#[test]
+	fn adaptive_stops_early_for_smooth() {
+		let mut rng = StdRng::seed_from_u64(0);
+		let normal = Direction::new(0f32, 0f32, 1f32);
+		let sun = Direction::normalized(0.3f32, 0f32, 1f32);
+
+		let (smooth, smooth_error, smooth_samples) = integrate_hemisphere_adaptive(&normal, |_x,_y,z| 1f32 + 0.1f32 * z, &mut rng, 0.05f32, 100000);
+		let expected = 2f32 * PI + 0.1f32 * PI;
+		assert!( (smooth - expected).abs() < 0.2, "Result is {0}, expected {1}", smooth, expected);
+		assert!(smooth_error < 0.05f32);
+
+		// Bright small sun in a dark sky
+		let (_peaky, _error, peaky_samples) = integrate_hemisphere_adaptive(&normal, |x,y,z| {
+			0.1f32 + 10f32 * (Direction::new(x,y,z).dot(&sun).max(0f32)).powi(50)
+		}, &mut rng, 0.05f32, 100000);
+		assert!(smooth_samples < peaky_samples, "Smooth took {0} samples, peaky {1}", smooth_samples, peaky_samples);
+
+		// Never takes more than max_samples
+		let (_result, _error, samples) = integrate_hemisphere_adaptive(&normal, |x,_y,_z| x, &mut rng, 0f32, 1000);
+		assert_eq!(samples, 1000);
+	}
+
+	#[test]
+	fn integrate_ring_constant() {
+		let mut rng = StdRng::seed_from_u64(0);
+		let axis = Direction::normalized(1f32, 0f32, 1f32);
+		let (inner, outer) = (0.3f32, 0.8f32);
+
+		let result = integrate_spherical_ring(&axis, inner, outer, |_x,_y,_z| 2f32, &mut rng, 1000);
+		let expected = 2f32 * 2f32 * PI * (inner.cos() - outer.cos());
+		assert!( (result - expected).abs() < 1e-4, "Result is {0}, expected {1}", result, expected);
+
+		// All samples must lie inside the ring
+		for _i in 0..1000 {
+			let angle = Direction::generate_random_in_ring(&axis, inner, outer, &mut rng).angle_to(&axis);
+			assert!(angle >= inner - 1e-3 && angle <= outer + 1e-3, "Angle {0} is outside of the ring", angle);
+		}
+	}
+
+	#[test]
+	fn mis_reduces_variance() {
+		use crate::sampler::CosineWeightedSampler;
+
+		let mut rng = StdRng::seed_from_u64(0);
+		let normal = Direction::new(0f32, 0f32, 1f32);
+		let light = Direction::normalized(1f32, 0f32, 0.5f32);
+
+		// Diffuse surface lit by a lobe. Cosine sampling around the normal matches
+		// the surface term, cosine sampling around the light matches the lighting
+		let func = |x: f32, y: f32, z: f32| {
+			let d = Direction::new(x, y, z);
+			d.dot(&normal).max(0f32) * (1f32 + d.dot(&light)).powi(3)
+		};
+		let expected: f32 = sphere_quadrature(40).iter().map(|(d, w)| w * func(d.x, d.y, d.z)).sum();
+
+		let mut surface = CosineWeightedSampler { normal };
+		let mut lighting = CosineWeightedSampler { normal: light };
+
+		// Mean squared error with the same number of samples for every estimator
+		let runs = 2000;
+		let mut error = |estimate: &mut dyn FnMut(&mut StdRng) -> f32| -> f32 {
+			(0..runs).map(|_run| (estimate(&mut rng) - expected).powi(2)).sum::<f32>() / runs as f32
+		};
+		let single = |sampler: &mut CosineWeightedSampler, rng: &mut StdRng| -> f32 {
+			(0..64).map(|_i| {
+				let (d, pdf) = sampler.next_direction(rng);
+				if pdf > 0f32 { func(d.x, d.y, d.z) / pdf } else { 0f32 }
+			}).sum::<f32>() / 64f32
+		};
+
+		let mis = error(&mut |rng| integrate_mis(&mut surface.clone(), &mut lighting.clone(), func, rng, 32));
+		let surface_only = error(&mut |rng| single(&mut surface, rng));
+		let lighting_only = error(&mut |rng| single(&mut lighting, rng));
+
+		assert!(mis < surface_only, "MIS error {0} is not lower than {1}", mis, surface_only);
+		assert!(mis < lighting_only, "MIS error {0} is not lower than {1}", mis, lighting_only);
+	}
+
+	#[test]
+	fn sphere_quadrature_exact() {
+		let nodes = sphere_quadrature(6);
+
+		let area: f32 = nodes.iter().map(|(_, w)| w).sum();
+		assert!( (area - 4f32 * PI).abs() < 1e-4, "Sphere area should be 4PI, got {0}", area);
+
+		// Integral of z^2 x^2 y^2 over sphere is 4PI/105
+		let integral: f32 = nodes.iter().map(|(d, w)| w * d.x*d.x * d.y*d.y * d.z*d.z).sum();
+		let expected = 4f32 * PI / 105f32;
+		assert!( (integral - expected).abs() < 1e-5, "Result is {0}, expected {1}", integral, expected);
+	}
+
+	#[test]
+	fn integrate_without_samples() {
+		let mut rng = StdRng::seed_from_u64(0);
+		let normal = Direction::new(0f32, 0f32, 1f32);
+		assert_eq!(integrate_real_space(|_x,_y,_z| 1f32, &mut rng, 0), 0f32);
+		assert_eq!(integrate_real_space_hemisphere(&normal, |_x,_y,_z| 1f32, &mut rng, 0), 0f32);
+	}
+
+	#[test]
+	fn integrate_constant() {
+		let mut rng = StdRng::seed_from_u64(0);
+
+		let sphere = integrate_real_space(|_x,_y,_z| 1f32, &mut rng, 1000);
+		assert!( (sphere - 4f32 * PI).abs() < 1e-4, "Result is {0}, expected {1}", sphere, 4f32 * PI);
+
+		let normal = Direction::new(0f32, 1f32, 0f32);
+		let hemisphere = integrate_real_space_hemisphere(&normal, |_x,_y,_z| 1f32, &mut rng, 1000);
+		assert!( (hemisphere - 2f32 * PI).abs() < 1e-4, "Result is {0}, expected {1}", hemisphere, 2f32 * PI);
+	}
 }